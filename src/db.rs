@@ -1,65 +1,391 @@
 use anyhow::{Result, anyhow};
+use clap::{ValueEnum, builder::PossibleValue};
 use directories::BaseDirs;
-use rusqlite::{Connection, params};
+use flac_codec::{decode::Metadata, metadata::Streaminfo};
+use rusqlite::{Connection, TransactionBehavior, params};
 use std::{
     path::{Path, PathBuf},
-    time::UNIX_EPOCH,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle, sleep},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::flac::{CURRENT_VENDOR, get_vendor};
+use crate::flac::{EncoderSettings, read_header, vendor_of};
 
 const TABLE_CREATE: &str = "CREATE TABLE IF NOT EXISTS flacs (path TEXT PRIMARY KEY UNIQUE, toencode BOOLEAN NOT NULL, modtime INTEGER)";
-const ADD_ITEM: &str = "INSERT INTO flacs (path, toencode, modtime) VALUES (?1, ?2, ?3)";
-const UPDATE_ITEM: &str = "UPDATE flacs SET toencode = ?2, modtime = ?3 WHERE path = ?1";
-const TOENCODE_PATHS: &str = "SELECT path FROM flacs WHERE toencode";
+const MIGRATIONS: [&str; 20] = [
+    "ALTER TABLE flacs ADD COLUMN size INTEGER",
+    "ALTER TABLE flacs ADD COLUMN tagpolicy TEXT",
+    "ALTER TABLE flacs ADD COLUMN truedepth INTEGER",
+    "ALTER TABLE flacs ADD COLUMN dualmono BOOLEAN",
+    "ALTER TABLE flacs ADD COLUMN lossy REAL",
+    "ALTER TABLE flacs ADD COLUMN cutoff INTEGER",
+    "ALTER TABLE flacs ADD COLUMN vendor TEXT",
+    // files indexed before vendors were recorded were done when they matched the old fixed target
+    "UPDATE flacs SET vendor = 'reference libFLAC 1.5.0 20250211' WHERE NOT toencode",
+    "ALTER TABLE flacs ADD COLUMN keptfor TEXT",
+    "ALTER TABLE flacs ADD COLUMN settings TEXT",
+    "ALTER TABLE flacs ADD COLUMN audited INTEGER",
+    "ALTER TABLE flacs ADD COLUMN auditerror TEXT",
+    "ALTER TABLE flacs ADD COLUMN nomd5 BOOLEAN",
+    "ALTER TABLE flacs ADD COLUMN md5 TEXT",
+    "ALTER TABLE flacs ADD COLUMN samples INTEGER",
+    "ALTER TABLE flacs ADD COLUMN samplerate INTEGER",
+    "ALTER TABLE flacs ADD COLUMN channels INTEGER",
+    "ALTER TABLE flacs ADD COLUMN bits INTEGER",
+    "ALTER TABLE flacs ADD COLUMN device INTEGER",
+    "ALTER TABLE flacs ADD COLUMN inode INTEGER",
+];
+const ADD_ITEM: &str = "INSERT OR REPLACE INTO flacs (path, toencode, modtime, size, vendor, nomd5) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+const UPDATE_ITEM: &str = "UPDATE flacs SET toencode = ?2, modtime = ?3, tagpolicy = NULL, truedepth = NULL, dualmono = NULL, keptfor = NULL, settings = NULL, audited = NULL, auditerror = NULL WHERE path = ?1";
+const UPDATE_SIZE: &str = "UPDATE flacs SET size = ?2 WHERE path = ?1";
+const UPDATE_STAT: &str = "UPDATE flacs SET modtime = ?2, size = ?3 WHERE path = ?1";
+const TOENCODE_PATHS: &str = "SELECT path, size FROM flacs WHERE toencode";
+const SET_VENDOR: &str = "UPDATE flacs SET vendor = ?2 WHERE path = ?1";
+const FLAG_VENDOR: &str = "UPDATE flacs SET toencode = TRUE WHERE NOT toencode AND vendor IS NOT NULL AND vendor != ?1 AND (keptfor IS NULL OR keptfor != ?1)";
+const SET_KEPT: &str = "UPDATE flacs SET keptfor = ?2 WHERE path = ?1";
+const SET_STREAM: &str = "UPDATE flacs SET md5 = ?2, samples = ?3, samplerate = ?4, channels = ?5, bits = ?6 WHERE path = ?1";
+const STREAMLESS_PATHS: &str = "SELECT path FROM flacs WHERE samplerate IS NULL";
+// files without an MD5 can't be told apart, so they are never reported as duplicates
+const DUPE_FILES: &str = "SELECT md5, samples, samplerate, channels, bits, path FROM (SELECT *, COUNT(*) OVER (PARTITION BY md5, samples, samplerate, channels, bits) AS copies FROM flacs WHERE md5 IS NOT NULL) WHERE copies > 1 ORDER BY md5, samples, samplerate, channels, bits, path";
+const SET_SETTINGS: &str = "UPDATE flacs SET settings = ?2 WHERE path = ?1";
+const RENAME_FILE: &str = "UPDATE flacs SET path = ?2 WHERE path = ?1";
+const SET_TAGPOLICY: &str = "UPDATE flacs SET tagpolicy = ?2 WHERE path = ?1";
+const SET_TRUEDEPTH: &str = "UPDATE flacs SET truedepth = ?2 WHERE path = ?1";
+const SET_DUALMONO: &str = "UPDATE flacs SET dualmono = ?2 WHERE path = ?1";
+const CLEAR_LOSSY: &str = "UPDATE flacs SET lossy = NULL, cutoff = NULL WHERE path = ?1";
+const CLEAR_RESHAPED_LOSSY: &str = "UPDATE flacs SET lossy = NULL, cutoff = NULL WHERE path = ?1 AND (channels IS NOT ?2 OR bits IS NOT ?3)";
+const SET_LOSSY: &str = "UPDATE flacs SET lossy = ?2, cutoff = ?3 WHERE path = ?1";
+const UNANALYSED_PATHS: &str = "SELECT path FROM flacs WHERE lossy IS NULL";
+const LOSSY_FILES: &str =
+    "SELECT path, lossy, cutoff FROM flacs WHERE lossy >= ?1 ORDER BY lossy DESC, path ASC";
+// files never audited come first, then the ones audited longest ago
+const AUDIT_PATHS: &str = "SELECT path FROM flacs WHERE audited IS NULL OR audited < ?1 ORDER BY audited IS NOT NULL, audited ASC, path ASC";
+const SET_AUDIT: &str = "UPDATE flacs SET audited = ?2, auditerror = ?3 WHERE path = ?1";
+const FAILED_AUDITS: &str =
+    "SELECT path, auditerror FROM flacs WHERE auditerror IS NOT NULL ORDER BY path ASC";
+// reencoding leaves the flag alone, so files that got their MD5 that way are still listed
+const NOMD5_PATHS: &str = "SELECT path FROM flacs WHERE nomd5 ORDER BY path ASC";
+const WRONG_VENDOR_PATHS: &str = "SELECT path FROM flacs WHERE vendor IS NOT NULL AND vendor != ?1";
+const SET_REVENDORED: &str =
+    "UPDATE flacs SET vendor = ?2, toencode = toencode AND nomd5 IS TRUE WHERE path = ?1";
+const TORETAG_PATHS: &str = "SELECT path FROM flacs WHERE tagpolicy IS NULL OR tagpolicy != ?1";
+const TORETAG_NUMBER: &str =
+    "SELECT COUNT(*) FROM flacs WHERE tagpolicy IS NULL OR tagpolicy != ?1";
 const TOENCODE_NUMBER: &str = "SELECT COUNT(*) from flacs WHERE toencode";
 const CHECK_FILE: &str = "SELECT exists(SELECT 1 FROM flacs WHERE path = ?1)";
 const FETCH_FILES: &str = "SELECT path FROM flacs";
 const REMOVE_FILE: &str = "DELETE FROM flacs WHERE path = ?1";
-const GET_MODTIME: &str = "SELECT modtime FROM flacs WHERE path = ?1";
+const GET_STAT: &str = "SELECT modtime, size, inode FROM flacs WHERE path = ?1";
+const SET_FILE_ID: &str = "UPDATE flacs SET device = ?2, inode = ?3 WHERE path = ?1";
+const LINKED_FILES: &str = "SELECT device, inode, path FROM (SELECT *, COUNT(*) OVER (PARTITION BY device, inode) AS links FROM flacs WHERE inode IS NOT NULL) WHERE links > 1 ORDER BY device, inode, path";
+const SOURCES_CREATE: &str =
+    "CREATE TABLE IF NOT EXISTS sources (path TEXT PRIMARY KEY UNIQUE, modtime INTEGER)";
+const ADD_SOURCE: &str = "INSERT OR REPLACE INTO sources (path, modtime) VALUES (?1, ?2)";
+const FETCH_SOURCES: &str = "SELECT path FROM sources";
+const REMOVE_SOURCE: &str = "DELETE FROM sources WHERE path = ?1";
+const RUNS_CREATE: &str = "CREATE TABLE IF NOT EXISTS runs (owner TEXT PRIMARY KEY UNIQUE, shared BOOLEAN NOT NULL, heartbeat INTEGER NOT NULL)";
+const CLAIMS_CREATE: &str = "CREATE TABLE IF NOT EXISTS claims (path TEXT PRIMARY KEY UNIQUE, owner TEXT NOT NULL, heartbeat INTEGER NOT NULL)";
+const LIVE_RUNS: &str =
+    "SELECT COUNT(*) FROM runs WHERE heartbeat >= ?1 AND (NOT shared OR NOT ?2)";
+const ADD_RUN: &str = "INSERT OR REPLACE INTO runs (owner, shared, heartbeat) VALUES (?1, ?2, ?3)";
+const REMOVE_RUN: &str = "DELETE FROM runs WHERE owner = ?1";
+const REMOVE_STALE_RUNS: &str = "DELETE FROM runs WHERE heartbeat < ?1";
+const HEARTBEAT_RUN: &str = "UPDATE runs SET heartbeat = ?2 WHERE owner = ?1";
+const HEARTBEAT_CLAIMS: &str = "UPDATE claims SET heartbeat = ?2 WHERE owner = ?1";
+const CLAIM_FILE: &str = "INSERT INTO claims (path, owner, heartbeat) SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM flacs WHERE path = ?1 AND toencode) ON CONFLICT(path) DO UPDATE SET owner = excluded.owner, heartbeat = excluded.heartbeat WHERE claims.heartbeat < ?4";
+const CLAIM_PATH: &str = "INSERT INTO claims (path, owner, heartbeat) VALUES (?1, ?2, ?3) ON CONFLICT(path) DO UPDATE SET owner = excluded.owner, heartbeat = excluded.heartbeat WHERE claims.heartbeat < ?4";
+const RELEASE_CLAIM: &str = "DELETE FROM claims WHERE path = ?1 AND owner = ?2";
+const RELEASE_CLAIMS: &str = "DELETE FROM claims WHERE owner = ?1";
 
-pub(crate) fn init_connection(path: Option<&PathBuf>) -> Result<Connection> {
-    let conn = if let Some(file) = path {
-        Connection::open(file)?
+/// How often a running instance refreshes its run and claim rows
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Rows whose heartbeat is older than this belong to a crashed run
+const STALE_SECS: u64 = 60;
+
+/// Order in which queued files are handed to the reencoding threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Order {
+    Any,
+    Largest,
+    Smallest,
+    Oldest,
+    Path,
+    Random,
+}
+
+impl Order {
+    fn clause(&self) -> &'static str {
+        match self {
+            Order::Any => "",
+            Order::Largest => " ORDER BY size DESC",
+            Order::Smallest => " ORDER BY size ASC",
+            Order::Oldest => " ORDER BY modtime ASC",
+            Order::Path => " ORDER BY path ASC",
+            Order::Random => " ORDER BY RANDOM()",
+        }
+    }
+}
+
+impl ValueEnum for Order {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Order::Any,
+            Order::Largest,
+            Order::Smallest,
+            Order::Oldest,
+            Order::Path,
+            Order::Random,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            Order::Any => "any",
+            Order::Largest => "largest",
+            Order::Smallest => "smallest",
+            Order::Oldest => "oldest",
+            Order::Path => "path",
+            Order::Random => "random",
+        }))
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+pub(crate) fn db_path(path: Option<&PathBuf>) -> Result<PathBuf> {
+    if let Some(file) = path {
+        Ok(file.to_owned())
     } else if let Some(base_dir) = BaseDirs::new() {
-        let file = Path::new(base_dir.data_dir()).join("reencoder.db");
-        Connection::open(file)?
+        Ok(Path::new(base_dir.data_dir()).join("reencoder.db"))
     } else {
-        return Err(anyhow!("Failed to locate data directory"));
-    };
+        Err(anyhow!("Failed to locate data directory"))
+    }
+}
+
+pub(crate) fn init_connection(path: Option<&PathBuf>) -> Result<Connection> {
+    let conn = Connection::open(db_path(path)?)?;
+    conn.busy_timeout(Duration::from_secs(30))?;
     conn.execute(TABLE_CREATE, ())?;
+    conn.execute(SOURCES_CREATE, ())?;
+    conn.execute(RUNS_CREATE, ())?;
+    conn.execute(CLAIMS_CREATE, ())?;
+    migrate(&conn)?;
     Ok(conn)
 }
 
-pub(crate) fn insert_file(conn: &Connection, filename: &Path) -> Result<()> {
-    let toencode = !matches!(get_vendor(filename)?.as_str(), CURRENT_VENDOR);
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute(migration, ())?;
+        conn.pragma_update(None, "user_version", index + 1)?;
+    }
+    Ok(())
+}
 
-    let modtime = filename
-        .metadata()?
-        .modified()?
-        .duration_since(UNIX_EPOCH)?
-        .as_secs();
+/// Indexes a file, queueing it unless it was written by the encoder with `target_vendor`.
+pub(crate) fn insert_file(conn: &Connection, filename: &Path, target_vendor: &str) -> Result<()> {
+    let (blocklist, foreign) = read_header(filename)?;
+    let vendor = vendor_of(&blocklist)?;
+    let streaminfo = blocklist.streaminfo();
+    let md5 = streaminfo.md5.is_some();
+    // files wrapped in foreign tags or lacking an MD5 are queued so reencoding can fix them
+    let toencode = vendor != target_vendor || !md5 || foreign.is_some();
+
+    let metadata = filename.metadata()?;
+    let modtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
 
     conn.execute(
         ADD_ITEM,
-        params![filename.to_str().unwrap(), toencode, modtime],
+        params![
+            filename.to_str().unwrap(),
+            toencode,
+            modtime,
+            metadata.len(),
+            vendor,
+            !md5
+        ],
     )?;
+    set_stream(conn, filename, Some(streaminfo))?;
+    set_file_id(conn, filename, &metadata)?;
 
     Ok(())
 }
 
 pub(crate) fn update_file(conn: &Connection, filename: &Path) -> Result<()> {
-    let modtime = filename
-        .metadata()?
-        .modified()?
-        .duration_since(UNIX_EPOCH)?
-        .as_secs();
+    let metadata = filename.metadata()?;
+    let modtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
 
     conn.execute(
         UPDATE_ITEM,
         params![filename.to_str().unwrap(), false, modtime],
     )?;
+    update_size(conn, filename, metadata.len())?;
+    let blocklist = read_header(filename).ok().map(|(blocklist, _)| blocklist);
+    conn.execute(
+        SET_VENDOR,
+        params![
+            filename.to_str().unwrap(),
+            blocklist
+                .as_ref()
+                .and_then(|blocklist| vendor_of(blocklist).ok())
+        ],
+    )?;
+    let streaminfo = blocklist.map(|blocklist| blocklist.streaminfo().clone());
+    // reencoding keeps the spectrum, unless the depth or channels were reduced
+    conn.execute(
+        CLEAR_RESHAPED_LOSSY,
+        params![
+            filename.to_str().unwrap(),
+            streaminfo.as_ref().map(|info| info.channel_count()),
+            streaminfo.as_ref().map(|info| info.bits_per_sample()),
+        ],
+    )?;
+    set_stream(conn, filename, streaminfo.as_ref())?;
+    set_file_id(conn, filename, &metadata)?;
+
+    Ok(())
+}
+
+/// Forgets the lossy origin analysis of a file whose audio may have changed.
+pub(crate) fn clear_lossy(conn: &Connection, filename: &Path) -> Result<()> {
+    conn.execute(CLEAR_LOSSY, params![filename.to_str().unwrap()])?;
+    Ok(())
+}
+
+/// Device and inode of a file, which hardlinks to it share
+pub(crate) fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Records the device and inode of a file, so its hardlinks can be found.
+pub(crate) fn set_file_id(
+    conn: &Connection,
+    filename: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<()> {
+    let id = file_id(metadata);
+    conn.execute(
+        SET_FILE_ID,
+        params![
+            filename.to_str().unwrap(),
+            id.map(|(device, _)| device),
+            id.map(|(_, inode)| inode)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns groups of indexed paths that are hardlinks to the same file, each sorted by path.
+pub(crate) fn get_linked_groups(conn: &Connection) -> Result<Vec<Vec<PathBuf>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(LINKED_FILES)?;
+    let mut rows = stmt.query([])?;
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut last = None;
+    while let Some(row) = rows.next()? {
+        let key: (u64, u64) = (row.get(0)?, row.get(1)?);
+        let path: String = row.get(2)?;
+        match groups.last_mut() {
+            Some(group) if last == Some(key) => group.push(PathBuf::from(path)),
+            _ => groups.push(vec![PathBuf::from(path)]),
+        }
+        last = Some(key);
+    }
+    Ok(groups)
+}
 
+/// Records the MD5, length and format of the audio, which duplicates are matched on.
+pub(crate) fn set_stream(
+    conn: &Connection,
+    filename: &Path,
+    streaminfo: Option<&Streaminfo>,
+) -> Result<()> {
+    let md5 = streaminfo.and_then(|info| info.md5).map(|md5| {
+        md5.iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    });
+    conn.execute(
+        SET_STREAM,
+        params![
+            filename.to_str().unwrap(),
+            md5,
+            streaminfo.and_then(|info| info.total_samples.map(|samples| samples.get())),
+            streaminfo.map(|info| info.sample_rate()),
+            streaminfo.map(|info| info.channel_count()),
+            streaminfo.map(|info| info.bits_per_sample()),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns files indexed before their audio's MD5, length and format were recorded.
+pub(crate) fn get_streamless_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(STREAMLESS_PATHS)?;
+    let mut rows = stmt.query([])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+/// Returns groups of files with bit-identical audio, each sorted by path.
+pub(crate) fn get_dupe_groups(conn: &Connection) -> Result<Vec<Vec<PathBuf>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(DUPE_FILES)?;
+    let mut rows = stmt.query([])?;
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut last = None;
+    while let Some(row) = rows.next()? {
+        let key: (String, Option<u64>, u32, u32, u32) = (
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        );
+        let path: String = row.get(5)?;
+        match groups.last_mut() {
+            Some(group) if last.as_ref() == Some(&key) => group.push(PathBuf::from(path)),
+            _ => groups.push(vec![PathBuf::from(path)]),
+        }
+        last = Some(key);
+    }
+    Ok(groups)
+}
+
+/// Records a file's new modification time and size without touching its queue state.
+pub(crate) fn refresh_file(conn: &Connection, filename: &Path) -> Result<()> {
+    let metadata = filename.metadata()?;
+    let modtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+
+    conn.execute(
+        UPDATE_STAT,
+        params![filename.to_str().unwrap(), modtime, metadata.len()],
+    )?;
+    // rewriting a file in place can give it a new inode
+    set_file_id(conn, filename, &metadata)?;
+
+    Ok(())
+}
+
+pub(crate) fn update_size(conn: &Connection, filename: &Path, size: u64) -> Result<()> {
+    conn.execute(UPDATE_SIZE, params![filename.to_str().unwrap(), size])?;
     Ok(())
 }
 
@@ -74,7 +400,7 @@ pub(crate) fn check_file(conn: &Connection, filename: &Path) -> Result<bool> {
     }
 }
 
-pub(crate) fn init_clean_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
+pub(crate) fn get_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
     let mut stmt = conn.prepare(FETCH_FILES)?;
     let mut rows = stmt.query(())?;
     let mut files = Vec::new();
@@ -85,22 +411,250 @@ pub(crate) fn init_clean_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqli
     Ok(files)
 }
 
+/// Queues an uncompressed file for conversion to FLAC.
+pub(crate) fn insert_source(conn: &Connection, filename: &Path) -> Result<()> {
+    let modtime = filename
+        .metadata()?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    conn.execute(ADD_SOURCE, params![filename.to_str().unwrap(), modtime])?;
+    Ok(())
+}
+
+pub(crate) fn get_sources(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(FETCH_SOURCES)?;
+    let mut rows = stmt.query(())?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+pub(crate) fn remove_source(conn: &Connection, filename: &Path) -> Result<()> {
+    conn.execute(REMOVE_SOURCE, params!(filename.to_str().unwrap()))?;
+    Ok(())
+}
+
+/// Moves a file's row to its new path, for files that changed container.
+pub(crate) fn rename_file(conn: &Connection, from: &Path, to: &Path) -> Result<()> {
+    conn.execute(
+        RENAME_FILE,
+        params![from.to_str().unwrap(), to.to_str().unwrap()],
+    )?;
+    Ok(())
+}
+
+/// Queues done files that were written by another encoder than the one with `target_vendor`,
+/// unless they were already recorded as done for it.
+///
+/// Returns the number of files queued.
+pub(crate) fn flag_vendor_mismatch(conn: &Connection, target_vendor: &str) -> Result<usize> {
+    Ok(conn.execute(FLAG_VENDOR, params![target_vendor])?)
+}
+
+/// Records that `file` counts as done for the target `vendor`, either because reencoding
+/// saved too little to keep or because it was just encoded under an overridden target.
+pub(crate) fn set_kept(conn: &Connection, file: &Path, vendor: &str) -> Result<()> {
+    conn.execute(SET_KEPT, params![file.to_str().unwrap(), vendor])?;
+    Ok(())
+}
+
+/// Records the trial settings that gave the smallest encode of `file`.
+pub(crate) fn set_settings(
+    conn: &Connection,
+    file: &Path,
+    settings: &EncoderSettings,
+) -> Result<()> {
+    conn.execute(
+        SET_SETTINGS,
+        params![file.to_str().unwrap(), settings.to_string()],
+    )?;
+    Ok(())
+}
+
 pub(crate) fn remove_file(conn: &Connection, filename: &Path) -> Result<()> {
     conn.execute(REMOVE_FILE, params!(filename.to_str().unwrap()))?;
     Ok(())
 }
 
-pub(crate) fn get_toencode_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
-    let mut stmt = conn.prepare(TOENCODE_PATHS)?;
+/// Returns queued files with their indexed size, if known.
+pub(crate) fn get_toencode_files(
+    conn: &Connection,
+    order: Order,
+) -> Result<Vec<(PathBuf, Option<u64>)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("{TOENCODE_PATHS}{}", order.clause()))?;
     let mut rows = stmt.query(())?;
-    let mut files: Vec<PathBuf> = Vec::new();
+    let mut files = Vec::new();
     while let Ok(Some(row)) = rows.next() {
+        let path: String = row.get(0)?;
+        let size: Option<u64> = row.get(1)?;
+        files.push((PathBuf::from(path), size));
+    }
+    Ok(files)
+}
+
+/// Records that the tag policy with `fingerprint` has been applied to a file.
+pub(crate) fn set_tagpolicy(conn: &Connection, file: &Path, fingerprint: &str) -> Result<()> {
+    conn.execute(SET_TAGPOLICY, params![file.to_str().unwrap(), fingerprint])?;
+    Ok(())
+}
+
+/// Records the number of bits actually used by a file's audio.
+pub(crate) fn set_truedepth(conn: &Connection, file: &Path, depth: u32) -> Result<()> {
+    conn.execute(SET_TRUEDEPTH, params![file.to_str().unwrap(), depth])?;
+    Ok(())
+}
+
+/// Records whether a stereo file's channels are identical.
+pub(crate) fn set_dualmono(conn: &Connection, file: &Path, dual_mono: bool) -> Result<()> {
+    conn.execute(SET_DUALMONO, params![file.to_str().unwrap(), dual_mono])?;
+    Ok(())
+}
+
+/// Records the lossy origin confidence and spectral cutoff of a file.
+pub(crate) fn set_lossy(
+    conn: &Connection,
+    file: &Path,
+    confidence: f64,
+    cutoff: u32,
+) -> Result<()> {
+    conn.execute(
+        SET_LOSSY,
+        params![file.to_str().unwrap(), confidence, cutoff],
+    )?;
+    Ok(())
+}
+
+/// Returns files that haven't been checked for lossy origin yet.
+pub(crate) fn get_unanalysed_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(UNANALYSED_PATHS)?;
+    let mut rows = stmt.query([])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+/// Returns files at least `threshold` likely to be of lossy origin, most suspicious first.
+pub(crate) fn get_lossy_files(
+    conn: &Connection,
+    threshold: f64,
+) -> Result<Vec<(PathBuf, f64, u32)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(LOSSY_FILES)?;
+    let mut rows = stmt.query(params![threshold])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push((PathBuf::from(path), row.get(1)?, row.get(2)?));
+    }
+    Ok(files)
+}
+
+/// Returns files last audited before the unix time `before`, least recently audited first.
+pub(crate) fn get_audit_files(
+    conn: &Connection,
+    before: u64,
+) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(AUDIT_PATHS)?;
+    let mut rows = stmt.query(params![before])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
         let path: String = row.get(0)?;
         files.push(PathBuf::from(path));
     }
     Ok(files)
 }
 
+/// Records that `file` was audited at the unix time `time`, with what was wrong with it if anything.
+pub(crate) fn set_audit(
+    conn: &Connection,
+    file: &Path,
+    time: u64,
+    error: Option<&str>,
+) -> Result<()> {
+    conn.execute(SET_AUDIT, params![file.to_str().unwrap(), time, error])?;
+    Ok(())
+}
+
+/// Returns files that failed their last audit, along with the error.
+pub(crate) fn get_failed_audits(
+    conn: &Connection,
+) -> Result<Vec<(PathBuf, String)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(FAILED_AUDITS)?;
+    let mut rows = stmt.query([])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push((PathBuf::from(path), row.get(1)?));
+    }
+    Ok(files)
+}
+
+/// Returns files that had no MD5 when they were indexed.
+pub(crate) fn get_nomd5_files(conn: &Connection) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(NOMD5_PATHS)?;
+    let mut rows = stmt.query([])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+/// Returns files the tag policy with `fingerprint` hasn't been applied to yet.
+pub(crate) fn get_toretag_files(
+    conn: &Connection,
+    fingerprint: &str,
+) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(TORETAG_PATHS)?;
+    let mut rows = stmt.query(params![fingerprint])?;
+    let mut files = Vec::new();
+    while let Ok(Some(row)) = rows.next() {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+/// Returns files whose vendor string isn't `vendor`.
+pub(crate) fn get_wrong_vendor_files(
+    conn: &Connection,
+    vendor: &str,
+) -> Result<Vec<PathBuf>, rusqlite::Error> {
+    let mut stmt = conn.prepare(WRONG_VENDOR_PATHS)?;
+    let mut rows = stmt.query(params![vendor])?;
+    let mut files = Vec::new();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        files.push(PathBuf::from(path));
+    }
+    Ok(files)
+}
+
+/// Records that the vendor string of `file` was rewritten to `vendor` in place.
+///
+/// The file stays queued only if it still lacks an MD5.
+pub(crate) fn set_revendored(conn: &Connection, file: &Path, vendor: &str) -> Result<()> {
+    conn.execute(SET_REVENDORED, params![file.to_str().unwrap(), vendor])?;
+    Ok(())
+}
+
+pub(crate) fn get_toretag_number(
+    conn: &Connection,
+    fingerprint: &str,
+) -> Result<u64, rusqlite::Error> {
+    conn.query_one(TORETAG_NUMBER, params![fingerprint], |row| {
+        let num: u64 = row.get(0)?;
+        Ok(num)
+    })
+}
+
 pub(crate) fn get_toencode_number(conn: &Connection) -> Result<u64, rusqlite::Error> {
     conn.query_one(TOENCODE_NUMBER, (), |row| {
         let num: u64 = row.get(0)?;
@@ -108,11 +662,14 @@ pub(crate) fn get_toencode_number(conn: &Connection) -> Result<u64, rusqlite::Er
     })
 }
 
-pub(crate) fn get_modtime(conn: &Connection, file: &Path) -> Result<u64> {
+/// Returns the indexed modification time and size of a file, and its inode if recorded.
+pub(crate) fn get_stat(conn: &Connection, file: &Path) -> Result<(u64, Option<u64>, Option<u64>)> {
     Ok(
-        conn.query_one(GET_MODTIME, params![file.to_str().unwrap()], |row| {
+        conn.query_one(GET_STAT, params![file.to_str().unwrap()], |row| {
             let modtime: u64 = row.get(0)?;
-            Ok(modtime)
+            let size: Option<u64> = row.get(1)?;
+            let inode: Option<u64> = row.get(2)?;
+            Ok((modtime, size, inode))
         })?,
     )
 }
@@ -122,11 +679,122 @@ pub(crate) fn vacuum(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// Claims a file for reencoding by `owner`.
+///
+/// Returns `false` if the file is no longer queued or another live run holds it.
+pub(crate) fn claim_file(conn: &Connection, file: &Path, owner: &str) -> Result<bool> {
+    let time = now()?;
+    let changed = conn.execute(
+        CLAIM_FILE,
+        params![file.to_str().unwrap(), owner, time, time - STALE_SECS],
+    )?;
+    Ok(changed == 1)
+}
+
+/// Claims any path for `owner`, queued for reencoding or not.
+///
+/// Returns `false` if another live run holds it.
+pub(crate) fn claim_path(conn: &Connection, file: &Path, owner: &str) -> Result<bool> {
+    let time = now()?;
+    let changed = conn.execute(
+        CLAIM_PATH,
+        params![file.to_str().unwrap(), owner, time, time - STALE_SECS],
+    )?;
+    Ok(changed == 1)
+}
+
+pub(crate) fn release_claim(conn: &Connection, file: &Path, owner: &str) -> Result<()> {
+    conn.execute(RELEASE_CLAIM, params![file.to_str().unwrap(), owner])?;
+    Ok(())
+}
+
+/// Advisory lock on the database held for the lifetime of a run.
+///
+/// A background thread keeps the run row and all of its file claims fresh,
+/// so rows left behind by a crashed instance go stale and can be reclaimed.
+pub(crate) struct RunLock {
+    owner: String,
+    stop: Arc<AtomicBool>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl RunLock {
+    /// Registers a new run, refusing to start if it would conflict with a live one.
+    ///
+    /// Exclusive runs conflict with any live run, shared runs only with exclusive ones.
+    pub(crate) fn acquire(path: Option<&PathBuf>, shared: bool) -> Result<Self> {
+        let mut conn = init_connection(path)?;
+        let owner = format!(
+            "{}-{}",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+        );
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let time = now()?;
+        tx.execute(REMOVE_STALE_RUNS, params![time - STALE_SECS])?;
+        let live: u64 = tx.query_one(LIVE_RUNS, params![time - STALE_SECS, shared], |row| {
+            row.get(0)
+        })?;
+        if live != 0 {
+            return Err(anyhow!(
+                "Database is in use by another instance, use --shared to cooperate with it"
+            ));
+        }
+        tx.execute(ADD_RUN, params![owner, shared, time])?;
+        tx.commit()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let heartbeat = {
+            let owner = owner.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut last = SystemTime::now();
+                while !stop.load(Ordering::SeqCst) {
+                    sleep(Duration::from_millis(100));
+                    if last.elapsed().unwrap_or_default() < HEARTBEAT_INTERVAL {
+                        continue;
+                    }
+                    last = SystemTime::now();
+                    if let Ok(time) = now() {
+                        let _ = conn.execute(HEARTBEAT_RUN, params![owner, time]);
+                        let _ = conn.execute(HEARTBEAT_CLAIMS, params![owner, time]);
+                    }
+                }
+                let _ = conn.execute(RELEASE_CLAIMS, params![owner]);
+                let _ = conn.execute(REMOVE_RUN, params![owner]);
+            })
+        };
+
+        Ok(RunLock {
+            owner,
+            stop,
+            heartbeat: Some(heartbeat),
+        })
+    }
+
+    pub(crate) fn owner(&self) -> &str {
+        &self.owner
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
+    /// Vendor string of the sample files
+    const VENDOR: &str = "reference libFLAC 1.5.0 20250211";
+
     #[test]
     fn check_localfiles() {
         let dbname = PathBuf::from("temp1.db");
@@ -139,7 +807,7 @@ mod tests {
         let conn = init_connection(Some(&dbname)).unwrap();
         for file in filenames {
             let filename = PathBuf::from(file);
-            insert_file(&conn, &filename).unwrap();
+            insert_file(&conn, &filename, VENDOR).unwrap();
         }
         let mut stmt = conn.prepare(TOENCODE_PATHS).unwrap();
         let mut returned = stmt.query(()).unwrap();
@@ -161,7 +829,7 @@ mod tests {
         ];
         let conn = init_connection(Some(&dbname)).unwrap();
         for file in filenames {
-            insert_file(&conn, &Path::new(file).canonicalize().unwrap()).unwrap();
+            insert_file(&conn, &Path::new(file).canonicalize().unwrap(), VENDOR).unwrap();
         }
 
         conn.execute(
@@ -193,4 +861,250 @@ mod tests {
         std::fs::remove_file(dbname).unwrap();
         assert!(counter == 0)
     }
+
+    #[test]
+    fn check_claims() {
+        let dbname = PathBuf::from("temp6.db");
+        let file = Path::new("./samples/16bit.flac").canonicalize().unwrap();
+        let conn = init_connection(Some(&dbname)).unwrap();
+        insert_file(&conn, &file, VENDOR).unwrap();
+        conn.execute(UPDATE_ITEM, params![file.to_str().unwrap(), true, 0])
+            .unwrap();
+
+        let first = RunLock::acquire(Some(&dbname), true).unwrap();
+        let second = RunLock::acquire(Some(&dbname), true).unwrap();
+        let exclusive = RunLock::acquire(Some(&dbname), false).is_err();
+
+        let claimed = claim_file(&conn, &file, first.owner()).unwrap();
+        let stolen = claim_file(&conn, &file, second.owner()).unwrap();
+
+        conn.execute("UPDATE claims SET heartbeat = 0", ()).unwrap();
+        let reclaimed = claim_file(&conn, &file, second.owner()).unwrap();
+
+        drop(first);
+        drop(second);
+        std::fs::remove_file(dbname).unwrap();
+        assert!(exclusive);
+        assert!(claimed && !stolen && reclaimed)
+    }
+
+    #[test]
+    fn check_tagpolicy() {
+        let dbname = PathBuf::from("temp8.db");
+        let file = Path::new("./samples/24bit.flac").canonicalize().unwrap();
+        let conn = init_connection(Some(&dbname)).unwrap();
+        insert_file(&conn, &file, VENDOR).unwrap();
+
+        let before = get_toretag_number(&conn, "policy").unwrap();
+        set_tagpolicy(&conn, &file, "policy").unwrap();
+        let after = get_toretag_number(&conn, "policy").unwrap();
+        let changed = get_toretag_files(&conn, "other").unwrap().len();
+        update_file(&conn, &file).unwrap();
+        let touched = get_toretag_number(&conn, "policy").unwrap();
+
+        std::fs::remove_file(dbname).unwrap();
+        assert!(before == 1 && after == 0 && changed == 1 && touched == 1)
+    }
+
+    fn get_truedepth(conn: &Connection, file: &Path) -> Option<u32> {
+        conn.query_row(
+            "SELECT truedepth FROM flacs WHERE path = ?1",
+            params![file.to_str().unwrap()],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn check_truedepth() {
+        let dbname = PathBuf::from("temp9.db");
+        let file = Path::new("./samples/24bit.flac").canonicalize().unwrap();
+        let conn = init_connection(Some(&dbname)).unwrap();
+        insert_file(&conn, &file, VENDOR).unwrap();
+
+        let before = get_truedepth(&conn, &file);
+        set_truedepth(&conn, &file, 16).unwrap();
+        let after = get_truedepth(&conn, &file);
+        update_file(&conn, &file).unwrap();
+        let touched = get_truedepth(&conn, &file);
+
+        std::fs::remove_file(dbname).unwrap();
+        assert!(before.is_none() && after == Some(16) && touched.is_none())
+    }
+
+    #[test]
+    fn check_lossy() {
+        let dbname = PathBuf::from("temp10.db");
+        let filenames = ["./samples/16bit.flac", "./samples/24bit.flac"]
+            .map(|name| Path::new(name).canonicalize().unwrap());
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, VENDOR).unwrap();
+        }
+
+        let before = get_unanalysed_files(&conn).unwrap().len();
+        set_lossy(&conn, &filenames[0], 0.9, 16000).unwrap();
+        set_lossy(&conn, &filenames[1], 0.1, 22000).unwrap();
+        let after = get_unanalysed_files(&conn).unwrap().len();
+        let lossy = get_lossy_files(&conn, 0.5).unwrap();
+        // same format, as after a plain reencode
+        update_file(&conn, &filenames[0]).unwrap();
+        let kept = get_unanalysed_files(&conn).unwrap().len();
+        clear_lossy(&conn, &filenames[0]).unwrap();
+        let cleared = get_unanalysed_files(&conn).unwrap();
+
+        std::fs::remove_file(dbname).unwrap();
+        assert!(before == 2 && after == 0 && kept == 0);
+        assert!(cleared == [filenames[0].clone()]);
+        assert!(lossy == [(filenames[0].clone(), 0.9, 16000)]);
+    }
+
+    #[test]
+    fn check_vendor() {
+        let dbname = PathBuf::from("temp11.db");
+        let filenames = ["./samples/16bit.flac", "./samples/24bit.flac"]
+            .map(|name| Path::new(name).canonicalize().unwrap());
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, VENDOR).unwrap();
+        }
+
+        let target = "reference libFLAC 1.4.3 20230623";
+        let unchanged = flag_vendor_mismatch(&conn, VENDOR).unwrap();
+        set_kept(&conn, &filenames[0], target).unwrap();
+        let flagged = flag_vendor_mismatch(&conn, target).unwrap();
+        let queued = get_toencode_number(&conn).unwrap();
+
+        std::fs::remove_file(dbname).unwrap();
+        assert!(unchanged == 0 && flagged == 1 && queued == 1);
+    }
+
+    #[test]
+    fn check_revendored() {
+        let dbname = PathBuf::from("temp16.db");
+        let filenames = ["./samples/16bit.flac", "./samples/24bit.flac"]
+            .map(|name| Path::new(name).canonicalize().unwrap());
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, "other").unwrap();
+        }
+
+        let wrong = get_wrong_vendor_files(&conn, "other").unwrap();
+        set_revendored(&conn, &filenames[0], "other").unwrap();
+        let remaining = get_wrong_vendor_files(&conn, "other").unwrap();
+        let queued = get_toencode_number(&conn).unwrap();
+
+        std::fs::remove_file(dbname).unwrap();
+        assert!(wrong.len() == 2 && remaining == [filenames[1].clone()] && queued == 1);
+    }
+
+    #[test]
+    fn check_audit() {
+        let dbname = PathBuf::from("temp12.db");
+        let filenames = [
+            "./samples/16bit.flac",
+            "./samples/24bit.flac",
+            "./samples/32bit.flac",
+        ]
+        .map(|name| Path::new(name).canonicalize().unwrap());
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, VENDOR).unwrap();
+        }
+
+        set_audit(&conn, &filenames[0], 200, None).unwrap();
+        set_audit(&conn, &filenames[1], 100, Some("MD5 mismatch")).unwrap();
+        let all = get_audit_files(&conn, 300).unwrap();
+        let stale = get_audit_files(&conn, 150).unwrap();
+        let failed = get_failed_audits(&conn).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(all == [2, 1, 0].map(|index| filenames[index].clone()));
+        assert!(stale == [2, 1].map(|index| filenames[index].clone()));
+        assert!(failed == [(filenames[1].clone(), "MD5 mismatch".to_string())]);
+    }
+
+    #[test]
+    fn check_nomd5() {
+        let dbname = PathBuf::from("temp13.db");
+        let filenames = ["./samples/16bit.flac", "./samples/dbnomd5.flac"]
+            .map(|name| Path::new(name).to_path_buf());
+        std::fs::copy(&filenames[0], &filenames[1]).unwrap();
+        flac_codec::metadata::update(&filenames[1], |blocklist| {
+            blocklist.streaminfo_mut().md5 = None;
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, VENDOR).unwrap();
+        }
+        let queued = get_toencode_number(&conn).unwrap();
+        let missing = get_nomd5_files(&conn).unwrap();
+        std::fs::remove_file(&filenames[1]).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(queued == 1 && missing == [filenames[1].clone()]);
+    }
+
+    #[test]
+    fn check_dupes() {
+        let dbname = PathBuf::from("temp14.db");
+        let filenames = [
+            "./samples/16bit.flac",
+            "./samples/24bit.flac",
+            "./samples/dupe.flac",
+        ]
+        .map(|name| Path::new(name).to_path_buf());
+        std::fs::copy(&filenames[0], &filenames[2]).unwrap();
+        flac_codec::metadata::update(&filenames[2], |blocklist| {
+            blocklist.update::<flac_codec::metadata::VorbisComment>(|comment| {
+                comment.fields.push("TITLE=Another Source".to_string())
+            });
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in &filenames {
+            insert_file(&conn, file, VENDOR).unwrap();
+        }
+        conn.execute(
+            "UPDATE flacs SET samplerate = NULL WHERE path = ?1",
+            [filenames[1].to_str()],
+        )
+        .unwrap();
+        let streamless = get_streamless_files(&conn).unwrap();
+        let groups = get_dupe_groups(&conn).unwrap();
+        std::fs::remove_file(&filenames[2]).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(streamless == [filenames[1].clone()]);
+        assert!(groups == [vec![filenames[0].clone(), filenames[2].clone()]]);
+    }
+
+    #[test]
+    fn check_order() {
+        let dbname = PathBuf::from("temp7.db");
+        let filenames = [
+            "./samples/16bit.flac",
+            "./samples/24bit.flac",
+            "./samples/32bit.flac",
+        ];
+        let conn = init_connection(Some(&dbname)).unwrap();
+        for file in filenames {
+            insert_file(&conn, Path::new(file), VENDOR).unwrap();
+        }
+        conn.execute("UPDATE flacs SET toencode = true", ())
+            .unwrap();
+
+        let largest = get_toencode_files(&conn, Order::Largest).unwrap();
+        let smallest = get_toencode_files(&conn, Order::Smallest).unwrap();
+        let path = get_toencode_files(&conn, Order::Path).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(largest.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(smallest.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert!(path.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(largest.len() == 3)
+    }
 }
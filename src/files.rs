@@ -1,10 +1,20 @@
 use crate::db;
-use crate::flac::handle_encode;
+use crate::flac::{
+    EncodeOptions, Outcome, audit_file, detect_lossy, fix_pictures, get_streaminfo, handle_convert,
+    handle_encode, has_md5, retag_file, temp_path,
+};
+use crate::ogg;
+use crate::pcm::is_pcm;
+use crate::pictures::PicturePolicy;
+use crate::tags::TagPolicy;
+use crate::throttle::load_average;
 use anyhow::{Result, anyhow};
+use clap::{ValueEnum, builder::PossibleValue};
 #[cfg(not(test))]
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rusqlite::Connection;
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::Display,
     path::{Path, PathBuf},
@@ -14,7 +24,7 @@ use std::{
         mpsc,
     },
     thread::{self, sleep},
-    time::{Duration, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use walkdir::WalkDir;
 
@@ -52,29 +62,185 @@ impl Display for FileError {
 
 impl Error for FileError {}
 
-fn handle_file(file: &Path, conn: &Connection) -> Result<()> {
+/// Upper bound on how much work a single reencoding run takes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    Files(usize),
+    Bytes(u64),
+}
+
+/// What to do with queued files that are hardlinked under several paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum HardlinkPolicy {
+    /// Reencode once and link the other indexed paths to the new file
+    #[default]
+    Relink,
+    /// Leave files with more than one link alone
+    Skip,
+}
+
+impl ValueEnum for HardlinkPolicy {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[HardlinkPolicy::Relink, HardlinkPolicy::Skip]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            HardlinkPolicy::Relink => "relink",
+            HardlinkPolicy::Skip => "skip",
+        }))
+    }
+}
+
+/// Number of hardlinks to a file
+fn link_count(metadata: &std::fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        1
+    }
+}
+
+/// Points `follower`, a hardlink of the file that was reencoded into `target`, at `target`.
+///
+/// The follower moves to a `.flac` path along with a file moved out of Ogg. Returns its
+/// path, or `None` if it stopped being a link to `old_id` and was left alone.
+fn relink(
+    conn: &Connection,
+    target: &Path,
+    follower: &Path,
+    old_id: Option<(u64, u64)>,
+    renamed: bool,
+) -> Result<Option<PathBuf>> {
+    let id = db::file_id(&follower.metadata()?);
+    if id == db::file_id(&target.metadata()?) {
+        return Ok(Some(follower.to_path_buf()));
+    }
+    if id != old_id {
+        return Ok(None);
+    }
+    let linked = if renamed {
+        follower.with_extension("flac")
+    } else {
+        follower.to_path_buf()
+    };
+    if renamed && linked.exists() {
+        return Err(anyhow!("{} already exists", linked.to_string_lossy()));
+    }
+    // linking next to the follower and renaming over it never leaves the path missing
+    let temp_name = temp_path(follower, "link.tmp");
+    let _ = std::fs::remove_file(&temp_name);
+    std::fs::hard_link(target, &temp_name)?;
+    std::fs::rename(&temp_name, &linked)?;
+    if renamed {
+        std::fs::remove_file(follower)?;
+        db::rename_file(conn, follower, &linked)?;
+    }
+    Ok(Some(linked))
+}
+
+/// Scheduling settings for a reencoding run
+#[derive(Debug, Clone)]
+pub(crate) struct RunOptions {
+    pub(crate) threads: usize,
+    pub(crate) order: db::Order,
+    pub(crate) limit: Option<Limit>,
+    /// No new files are started past this point
+    pub(crate) deadline: Option<Instant>,
+    /// No new files are started once this many source bytes were taken on
+    pub(crate) max_bytes: Option<u64>,
+    /// Drop to a single thread while the load average is above this
+    pub(crate) max_load: Option<f64>,
+    pub(crate) hardlinks: HardlinkPolicy,
+    pub(crate) encode: EncodeOptions,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            threads: 4,
+            order: db::Order::Any,
+            limit: None,
+            deadline: None,
+            max_bytes: None,
+            max_load: None,
+            hardlinks: HardlinkPolicy::Relink,
+            encode: EncodeOptions::default(),
+        }
+    }
+}
+
+impl RunOptions {
+    /// Returns why the run has to stop taking new files, if it does
+    fn exhausted(&self, bytes_taken: u64) -> Option<&'static str> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some("Time limit reached")
+        } else if self.max_bytes.is_some_and(|budget| bytes_taken >= budget) {
+            Some("Byte budget reached")
+        } else {
+            None
+        }
+    }
+
+    /// Number of threads allowed to run right now, given `active` encodes of ours
+    /// that already count towards the load average
+    fn allowed_threads(&self, active: usize) -> usize {
+        match (self.max_load, load_average()) {
+            (Some(max_load), Some(load)) if load > max_load + active as f64 => 1,
+            _ => self.threads,
+        }
+    }
+}
+
+fn handle_file(file: &Path, conn: &Connection, target_vendor: &str) -> Result<()> {
+    if is_pcm(file) {
+        // sources already converted and kept are left alone
+        if !file.with_extension("flac").exists() {
+            db::insert_source(conn, file)?;
+        }
+        return Ok(());
+    }
+
     if db::check_file(conn, file)? {
-        let modtime = file
-            .metadata()?
-            .modified()?
-            .duration_since(UNIX_EPOCH)?
-            .as_secs();
-        let db_modtime = db::get_modtime(conn, file)?;
+        let metadata = file.metadata()?;
+        let modtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+        let (db_modtime, db_size, db_inode) = db::get_stat(conn, file)?;
         if modtime != db_modtime {
             db::update_file(conn, file)?;
+            db::clear_lossy(conn, file)?;
+        } else {
+            if db_size.is_none() {
+                db::update_size(conn, file, metadata.len())?;
+            }
+            if db_inode.is_none() {
+                db::set_file_id(conn, file, &metadata)?;
+            }
         }
         return Ok(());
     }
 
-    db::insert_file(conn, file)?;
+    db::insert_file(conn, file, target_vendor)?;
 
     Ok(())
 }
 
+/// Indexes the FLAC files under `path`, and with `sources` also the files to convert.
+///
+/// New files are queued unless they were written by the encoder with `target_vendor`.
 pub(crate) fn index_files_recursively(
     path: &Path,
     conn: &Connection,
     handler: Arc<AtomicBool>,
+    sources: bool,
+    target_vendor: &str,
 ) -> Result<()> {
     if !path.is_dir() {
         return Err(anyhow!("Invalid root directory"));
@@ -105,7 +271,10 @@ pub(crate) fn index_files_recursively(
                         if !path.is_file() {
                             continue;
                         }
-                        if path.extension().is_some_and(|x| x == "flac") {
+                        if path.extension().is_some_and(|x| x == "flac")
+                            || (ogg::is_ogg(&path) && ogg::is_ogg_flac(&path))
+                            || (sources && is_pcm(&path))
+                        {
                             let _ = filesend.send(path.to_owned());
                             #[cfg(not(test))]
                             newbar.inc_length(1);
@@ -117,11 +286,15 @@ pub(crate) fn index_files_recursively(
             }
         });
 
-        while let Ok(path) = filerecv.recv()
-            && handler.load(Ordering::SeqCst)
-        {
+        while handler.load(Ordering::SeqCst) {
+            // don't block on a slow walker so a signal is noticed right away
+            let path = match filerecv.recv_timeout(Duration::from_millis(100)) {
+                Ok(path) => path,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
             #[allow(unused_variables)]
-            if let Err(error) = handle_file(&path, conn) {
+            if let Err(error) = handle_file(&path, conn, target_vendor) {
                 #[cfg(not(test))]
                 bar.println(format!("{}", FileError::new(&path, error)));
             } else {
@@ -142,28 +315,111 @@ pub(crate) fn index_files_recursively(
     Ok(())
 }
 
+/// Cuts the ordered queue down to what fits into `limit`.
+///
+/// With a byte limit, files that would overshoot it are passed over so smaller ones can still fit.
+fn limit_queue(files: Vec<(PathBuf, Option<u64>)>, limit: Option<Limit>) -> Vec<PathBuf> {
+    match limit {
+        None => files.into_iter().map(|(file, _)| file).collect(),
+        Some(Limit::Files(count)) => files
+            .into_iter()
+            .take(count)
+            .map(|(file, _)| file)
+            .collect(),
+        Some(Limit::Bytes(budget)) => {
+            let mut left = budget;
+            files
+                .into_iter()
+                .filter_map(|(file, size)| {
+                    let size = size
+                        .or_else(|| file.metadata().ok().map(|metadata| metadata.len()))
+                        .unwrap_or(0);
+                    if size <= left {
+                        left -= size;
+                        Some(file)
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// Reencodes queued files until the queue or a limit runs out.
+///
+/// Clearing `handler` stops new files from being started, clearing `encoder_handler`
+/// also abandons the running encodes and removes their temporary files.
 pub(crate) fn reencode_files(
     conn: Connection,
     handler: Arc<AtomicBool>,
-    threads: usize,
+    encoder_handler: Arc<AtomicBool>,
+    options: &RunOptions,
+    owner: &str,
 ) -> Result<()> {
+    let queued = db::get_toencode_files(&conn, options.order)?;
+    // each set of hardlinks is reencoded once, through the first of its paths that is queued
+    let mut followers = HashMap::new();
+    if options.hardlinks == HardlinkPolicy::Relink {
+        let queued_paths = queued.iter().map(|(file, _)| file).collect::<HashSet<_>>();
+        for group in db::get_linked_groups(&conn)? {
+            if let Some(leader) = group.iter().find(|file| queued_paths.contains(file)) {
+                let leader = leader.clone();
+                let others = group
+                    .into_iter()
+                    .filter(|file| *file != leader)
+                    .collect::<Vec<_>>();
+                followers.insert(leader, others);
+            }
+        }
+    }
+    let following = followers
+        .values()
+        .flatten()
+        .cloned()
+        .collect::<HashSet<PathBuf>>();
+    let files = limit_queue(
+        queued
+            .into_iter()
+            .filter(|(file, _)| !following.contains(file))
+            .collect(),
+        options.limit,
+    );
+
     #[cfg(not(test))]
     let bar = ProgressBar::with_draw_target(
-        Some(db::get_toencode_number(&conn)?),
+        Some(files.len() as u64),
         ProgressDrawTarget::stdout_with_hz(60),
     )
     .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
     .with_message("Reencoding");
 
-    let mut files = db::get_toencode_files(&conn)?.into_iter();
+    let mut files = files.into_iter();
 
     let lock = Arc::new(Mutex::new(conn));
+    let fingerprint = options.encode.tags.fingerprint();
 
     let thread_counter = Arc::new(AtomicUsize::new(0));
 
+    let mut bytes_taken = 0;
+    #[cfg_attr(test, allow(unused_variables))]
+    let mut stopped = None;
+    #[cfg_attr(test, allow(unused_variables))]
+    let mut linked_skipped = 0;
+
     thread::scope(|s| {
-        while handler.load(Ordering::SeqCst) {
-            if thread_counter.load(Ordering::Relaxed) >= threads {
+        loop {
+            if !handler.load(Ordering::SeqCst) {
+                stopped = Some("Reencoding stopped");
+                break;
+            }
+            if let Some(reason) = options.exhausted(bytes_taken) {
+                stopped = Some(reason);
+                break;
+            }
+
+            let active = thread_counter.load(Ordering::Relaxed);
+            if active >= options.allowed_threads(active) {
                 sleep(Duration::from_millis(100));
                 #[cfg(not(test))]
                 bar.tick();
@@ -175,49 +431,665 @@ pub(crate) fn reencode_files(
                 None => break,
             };
 
+            let metadata = file.metadata().ok();
+            if options.hardlinks == HardlinkPolicy::Skip
+                && metadata
+                    .as_ref()
+                    .is_some_and(|metadata| link_count(metadata) > 1)
+            {
+                linked_skipped += 1;
+                #[cfg(not(test))]
+                bar.dec_length(1);
+                continue;
+            }
+
+            match db::claim_file(&lock.lock().unwrap(), &file, owner) {
+                Ok(true) => {}
+                Ok(false) => {
+                    #[cfg(not(test))]
+                    bar.dec_length(1);
+                    continue;
+                }
+                #[allow(unused_variables)]
+                Err(error) => {
+                    #[cfg(not(test))]
+                    bar.println(format!("{}", FileError::new(&file, error)));
+                    continue;
+                }
+            }
+
+            bytes_taken += metadata.as_ref().map_or(0, |metadata| metadata.len());
             thread_counter.fetch_add(1, Ordering::Relaxed);
+            let followers = followers.get(&file).cloned().unwrap_or_default();
+            let old_id = metadata.as_ref().and_then(db::file_id);
+            #[cfg(not(test))]
+            let links = metadata.as_ref().map_or(1, link_count);
 
-            let handler = handler.clone();
+            let handler = encoder_handler.clone();
             let lock = lock.clone();
             #[cfg(not(test))]
             let bar = bar.clone();
             let thread_counter = thread_counter.clone();
+            let fingerprint = &fingerprint;
 
             s.spawn(move || {
                 #[allow(unused_variables)]
-                match handle_encode(&file, handler) {
+                match handle_encode(&file, handler, &options.encode) {
                     Err(error) => {
                         #[cfg(not(test))]
                         bar.println(format!("{}", FileError::new(&file, error)));
                     }
-                    Ok(false) => {
-                        if let Err(error) = db::update_file(&lock.lock().unwrap(), &file) {
+                    Ok(Outcome { aborted: true, .. }) => {}
+                    #[allow(unused_variables)]
+                    Ok(Outcome {
+                        notes,
+                        true_depth,
+                        dual_mono,
+                        renamed,
+                        kept,
+                        settings,
+                        ..
+                    }) => {
+                        #[cfg(not(test))]
+                        for note in notes {
+                            bar.println(format!("{}:\t{}", file.to_string_lossy(), note));
+                        }
+                        let conn = lock.lock().unwrap();
+                        // files moved out of Ogg keep their row under the new name
+                        let target = renamed.as_deref().unwrap_or(&file);
+                        let record = |target: &Path| {
+                            db::update_file(&conn, target)
+                                // kept originals never had the tag policy applied
+                                .and_then(|_| match &kept {
+                                    Some(vendor) => db::set_kept(&conn, target, vendor),
+                                    None => db::set_tagpolicy(&conn, target, fingerprint),
+                                })
+                                // the new file has the encoder's vendor, not an overridden target
+                                .and_then(|_| match (&kept, &options.encode.vendor) {
+                                    (None, Some(vendor)) => db::set_kept(&conn, target, vendor),
+                                    _ => Ok(()),
+                                })
+                                .and_then(|_| match true_depth {
+                                    Some(depth) => db::set_truedepth(&conn, target, depth),
+                                    None => Ok(()),
+                                })
+                                .and_then(|_| match dual_mono {
+                                    Some(dual_mono) => db::set_dualmono(&conn, target, dual_mono),
+                                    None => Ok(()),
+                                })
+                                .and_then(|_| match &settings {
+                                    Some(settings) => db::set_settings(&conn, target, settings),
+                                    None => Ok(()),
+                                })
+                        };
+                        if let Err(error) = match &renamed {
+                            Some(target) => db::rename_file(&conn, &file, target),
+                            None => Ok(()),
+                        }
+                        .and_then(|_| record(target))
+                        {
                             #[cfg(not(test))]
                             bar.println(format!("{}", FileError::new(&file, error)));
                         }
+                        for follower in &followers {
+                            if let Err(error) =
+                                relink(&conn, target, follower, old_id, renamed.is_some()).and_then(
+                                    |linked| match linked {
+                                        Some(linked) => record(&linked),
+                                        None => Ok(()),
+                                    },
+                                )
+                            {
+                                #[cfg(not(test))]
+                                bar.println(format!("{}", FileError::new(follower, error)));
+                            }
+                        }
+                        #[cfg(not(test))]
+                        if links > followers.len() as u64 + 1
+                            && target
+                                .metadata()
+                                .is_ok_and(|metadata| db::file_id(&metadata) != old_id)
+                        {
+                            bar.println(format!(
+                                "{}:\t{} hardlinks outside the index still hold the old file",
+                                file.to_string_lossy(),
+                                links - followers.len() as u64 - 1
+                            ));
+                        }
                         #[cfg(not(test))]
                         bar.inc(1)
                     }
-                    Ok(true) => {}
                 };
+                let _ = db::release_claim(&lock.lock().unwrap(), &file, owner);
                 thread_counter.fetch_sub(1, Ordering::Relaxed);
             });
         }
+
+        #[cfg(not(test))]
+        if stopped.is_some() && thread_counter.load(Ordering::Relaxed) > 0 {
+            bar.set_message("Finishing running encodes");
+        }
     });
 
     #[cfg(not(test))]
     {
-        if handler.load(Ordering::SeqCst) {
+        if !encoder_handler.load(Ordering::SeqCst) {
+            bar.abandon_with_message("Reencoding aborted");
+        } else if let Some(reason) = stopped {
+            bar.abandon_with_message(reason);
+        } else {
             bar.finish_with_message("Finished reencoding");
+        }
+        if stopped.is_some() {
+            println!(
+                "Files left to reencode:\t{}",
+                console::style(db::get_toencode_number(&lock.lock().unwrap())?).yellow()
+            );
+        }
+        if linked_skipped > 0 {
+            println!(
+                "Hardlinked files skipped:\t{}",
+                console::style(linked_skipped).yellow()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Applies the tag policy to indexed files that haven't seen it yet, without reencoding anything.
+///
+/// With `vendor`, files with another vendor string get it rewritten in place instead of
+/// waiting to be reencoded.
+pub(crate) fn retag_files(
+    conn: &Connection,
+    handler: Arc<AtomicBool>,
+    policy: &TagPolicy,
+    vendor: Option<&str>,
+    owner: &str,
+) -> Result<()> {
+    let fingerprint = policy.fingerprint();
+    let mut files = db::get_toretag_files(conn, &fingerprint)?;
+    if let Some(vendor) = vendor {
+        let queued = files.iter().cloned().collect::<HashSet<_>>();
+        files.extend(
+            db::get_wrong_vendor_files(conn, vendor)?
+                .into_iter()
+                .filter(|file| !queued.contains(file)),
+        );
+    }
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Retagging");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst) {
+            break;
+        }
+        let retagged = db::claim_path(conn, &file, owner).and_then(|claimed| {
+            if !claimed {
+                return Ok(());
+            }
+            let retagged = retag_file(&file, policy, vendor).and_then(|changed| {
+                if changed {
+                    db::refresh_file(conn, &file)?;
+                }
+                if let Some(vendor) = vendor {
+                    db::set_revendored(conn, &file, vendor)?;
+                }
+                db::set_tagpolicy(conn, &file, &fingerprint)
+            });
+            db::release_claim(conn, &file, owner)?;
+            retagged
+        });
+        #[allow(unused_variables)]
+        if let Err(error) = retagged {
+            #[cfg(not(test))]
+            bar.println(format!("{}", FileError::new(&file, error)));
+        }
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+
+    #[cfg(not(test))]
+    {
+        if handler.load(Ordering::SeqCst) {
+            bar.finish_with_message("Finished retagging");
         } else {
-            bar.abandon_with_message("Reencoding aborted");
+            bar.abandon_with_message("Retagging aborted");
+        }
+    }
+    Ok(())
+}
+
+/// Converts queued WAV, AIFF and W64 files to FLAC and indexes the results.
+///
+/// With `remove_sources`, sources are deleted once their FLAC file has been verified.
+/// The new files are queued unless the encoder has `encoder_vendor`.
+pub(crate) fn convert_files(
+    conn: &Connection,
+    handler: Arc<AtomicBool>,
+    encoder_handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+    remove_sources: bool,
+    encoder_vendor: &str,
+    owner: &str,
+) -> Result<()> {
+    let files = db::get_sources(conn)?;
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Converting");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst) {
+            break;
+        }
+        if !file.exists() {
+            db::remove_source(conn, &file)?;
+            continue;
+        }
+        match db::claim_path(conn, &file, owner) {
+            Ok(true) => {}
+            Ok(false) => {
+                #[cfg(not(test))]
+                bar.dec_length(1);
+                continue;
+            }
+            #[allow(unused_variables)]
+            Err(error) => {
+                #[cfg(not(test))]
+                bar.println(format!("{}", FileError::new(&file, error)));
+                continue;
+            }
+        }
+        match handle_convert(&file, encoder_handler.clone(), options) {
+            Ok(Outcome { aborted: true, .. }) => {
+                db::release_claim(conn, &file, owner)?;
+                break;
+            }
+            Ok(Outcome {
+                dual_mono,
+                settings,
+                ..
+            }) => {
+                let target = file.with_extension("flac");
+                #[allow(unused_variables)]
+                if let Err(error) = db::insert_file(conn, &target, encoder_vendor)
+                    .and_then(|_| match &options.vendor {
+                        Some(vendor) => db::set_kept(conn, &target, vendor),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match dual_mono {
+                        Some(dual_mono) => db::set_dualmono(conn, &target, dual_mono),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match &settings {
+                        Some(settings) => db::set_settings(conn, &target, settings),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| {
+                        if remove_sources {
+                            std::fs::remove_file(&file)?;
+                        }
+                        db::remove_source(conn, &file)
+                    })
+                {
+                    #[cfg(not(test))]
+                    bar.println(format!("{}", FileError::new(&file, error)));
+                }
+            }
+            #[allow(unused_variables)]
+            Err(error) => {
+                #[cfg(not(test))]
+                bar.println(format!("{}", FileError::new(&file, error)));
+            }
+        }
+        db::release_claim(conn, &file, owner)?;
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+
+    #[cfg(not(test))]
+    {
+        if handler.load(Ordering::SeqCst) && encoder_handler.load(Ordering::SeqCst) {
+            bar.finish_with_message("Finished converting");
+        } else {
+            bar.abandon_with_message("Converting aborted");
+        }
+    }
+    Ok(())
+}
+
+/// Applies the picture policy to every indexed file without reencoding anything
+pub(crate) fn picture_files(
+    conn: &Connection,
+    handler: Arc<AtomicBool>,
+    policy: &PicturePolicy,
+    owner: &str,
+) -> Result<()> {
+    let files = db::get_files(conn)?;
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Fixing pictures");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst) {
+            break;
+        }
+        let fixed = db::claim_path(conn, &file, owner).and_then(|claimed| {
+            if !claimed {
+                return Ok(Vec::new());
+            }
+            let fixed = fix_pictures(&file, policy).and_then(|(changed, notes)| {
+                if changed {
+                    db::refresh_file(conn, &file)?;
+                }
+                Ok(notes)
+            });
+            db::release_claim(conn, &file, owner)?;
+            fixed
+        });
+        match fixed {
+            #[allow(unused_variables)]
+            Ok(notes) =>
+            {
+                #[cfg(not(test))]
+                for note in notes {
+                    bar.println(format!("{}:\t{}", file.to_string_lossy(), note));
+                }
+            }
+            #[allow(unused_variables)]
+            Err(error) => {
+                #[cfg(not(test))]
+                bar.println(format!("{}", FileError::new(&file, error)));
+            }
+        }
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+
+    #[cfg(not(test))]
+    {
+        if handler.load(Ordering::SeqCst) {
+            bar.finish_with_message("Finished fixing pictures");
+        } else {
+            bar.abandon_with_message("Fixing pictures aborted");
+        }
+    }
+    Ok(())
+}
+
+/// Checks every file not analysed yet for signs of lossy origin, without modifying any of them
+pub(crate) fn detect_lossy_files(conn: &Connection, handler: Arc<AtomicBool>) -> Result<()> {
+    let files = db::get_unanalysed_files(conn)?;
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Analysing");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst) {
+            break;
+        }
+        #[allow(unused_variables)]
+        if let Err(error) = detect_lossy(&file, handler.clone()).and_then(|report| match report {
+            Some(report) => db::set_lossy(conn, &file, report.confidence, report.cutoff),
+            None => Ok(()),
+        }) {
+            #[cfg(not(test))]
+            bar.println(format!("{}", FileError::new(&file, error)));
+        }
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+
+    #[cfg(not(test))]
+    {
+        if handler.load(Ordering::SeqCst) {
+            bar.finish_with_message("Finished analysing");
+        } else {
+            bar.abandon_with_message("Analysis aborted");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the files likely to be of lossy origin
+pub(crate) fn lossy_report(conn: &Connection, threshold: f64) -> Result<()> {
+    let files = db::get_lossy_files(conn, threshold)?;
+    for (file, confidence, cutoff) in &files {
+        println!(
+            "{}\t{:>6} Hz\t{}",
+            console::style(format!("{confidence:.2}")).red(),
+            cutoff,
+            file.to_string_lossy()
+        );
+    }
+    println!(
+        "Likely lossy files: {}",
+        console::style(files.len()).yellow()
+    );
+    Ok(())
+}
+
+/// Decodes indexed files not audited within `max_age`, least recently audited first, recording
+/// when each was checked and what was wrong with it. No new file is started after `deadline`.
+pub(crate) fn audit_files(
+    conn: &Connection,
+    handler: Arc<AtomicBool>,
+    max_age: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let files = db::get_audit_files(
+        conn,
+        now.saturating_sub(max_age.unwrap_or_default()).as_secs(),
+    )?;
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Auditing");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst)
+            || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            break;
+        }
+        // missing files are left to --clean
+        if !file.exists() {
+            continue;
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        #[allow(unused_variables)]
+        let result = match audit_file(&file, handler.clone()) {
+            Ok(false) => break,
+            Ok(true) => db::set_audit(conn, &file, time, None),
+            Err(error) => {
+                let message = format!("{error:#}");
+                #[cfg(not(test))]
+                bar.println(format!("{}", FileError::new(&file, error)));
+                db::set_audit(conn, &file, time, Some(&message))
+            }
+        };
+        #[allow(unused_variables)]
+        if let Err(error) = result {
+            #[cfg(not(test))]
+            bar.println(format!("{}", FileError::new(&file, error)));
+        }
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+
+    #[cfg(not(test))]
+    {
+        if handler.load(Ordering::SeqCst) {
+            bar.finish_with_message("Finished auditing");
+        } else {
+            bar.abandon_with_message("Audit aborted");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the files that failed their last audit
+pub(crate) fn audit_report(conn: &Connection) -> Result<()> {
+    let files = db::get_failed_audits(conn)?;
+    for (file, error) in &files {
+        println!(
+            "{}\t{}",
+            console::style(error).red(),
+            file.to_string_lossy()
+        );
+    }
+    println!("Failed audits: {}", console::style(files.len()).yellow());
+    Ok(())
+}
+
+/// Prints the files that had no MD5 when indexed, and whether they have one now
+pub(crate) fn md5_report(conn: &Connection) -> Result<()> {
+    let files = db::get_nomd5_files(conn)?;
+    for file in &files {
+        let status = match has_md5(file) {
+            Ok(true) => console::style("computed".to_string()).green(),
+            Ok(false) => console::style("missing".to_string()).red(),
+            Err(error) => console::style(format!("{error:#}")).yellow(),
+        };
+        println!("{}\t{}", status, file.to_string_lossy());
+    }
+    println!(
+        "Files indexed without an MD5: {}",
+        console::style(files.len()).yellow()
+    );
+    Ok(())
+}
+
+/// Shell script printed instead of the list of duplicates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DupeScript {
+    /// Replace each duplicate with a hardlink to the first file of its group
+    Hardlink,
+    /// Delete all but the first file of each group
+    Delete,
+}
+
+impl ValueEnum for DupeScript {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[DupeScript::Hardlink, DupeScript::Delete]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            DupeScript::Hardlink => "hardlink",
+            DupeScript::Delete => "delete",
+        }))
+    }
+}
+
+/// Quotes `path` for a POSIX shell
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Prints groups of files with bit-identical audio, or with `script` a shell script dealing
+/// with all but the first file of each group.
+///
+/// Files indexed before their audio was recorded are read first.
+pub(crate) fn dupes_report(
+    conn: &Connection,
+    handler: Arc<AtomicBool>,
+    script: Option<DupeScript>,
+) -> Result<()> {
+    let files = db::get_streamless_files(conn)?;
+
+    #[cfg(not(test))]
+    let bar = ProgressBar::with_draw_target(
+        Some(files.len() as u64),
+        ProgressDrawTarget::stdout_with_hz(60),
+    )
+    .with_style(ProgressStyle::with_template(BAR_TEMPLATE)?.progress_chars("#>-"))
+    .with_message("Reading stream info");
+
+    for file in files {
+        if !handler.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        #[allow(unused_variables)]
+        if let Err(error) =
+            get_streaminfo(&file).and_then(|info| db::set_stream(conn, &file, Some(&info)))
+        {
+            #[cfg(not(test))]
+            bar.println(format!("{}", FileError::new(&file, error)));
+        }
+        #[cfg(not(test))]
+        bar.inc(1);
+    }
+    #[cfg(not(test))]
+    bar.finish_and_clear();
+
+    let groups = db::get_dupe_groups(conn)?;
+    let copies = groups.iter().map(|group| group.len() - 1).sum::<usize>();
+    match script {
+        None => {
+            for group in &groups {
+                println!("{}", console::style(group[0].to_string_lossy()).green());
+                for file in &group[1..] {
+                    println!("{}", file.to_string_lossy());
+                }
+                println!();
+            }
+            println!(
+                "Duplicate groups: {}, redundant copies: {}",
+                console::style(groups.len()).yellow(),
+                console::style(copies).yellow()
+            );
+        }
+        Some(script) => {
+            println!("#!/bin/sh");
+            for group in &groups {
+                let keep = shell_quote(&group[0]);
+                println!("\n# keeping {keep}");
+                for file in &group[1..] {
+                    match script {
+                        DupeScript::Hardlink => println!("ln -f -- {keep} {}", shell_quote(file)),
+                        DupeScript::Delete => println!("rm -- {}", shell_quote(file)),
+                    }
+                }
+            }
+            // the script itself goes to stdout
+            eprintln!(
+                "Duplicate groups: {}, redundant copies: {copies}",
+                groups.len()
+            );
         }
     }
     Ok(())
 }
 
 pub(crate) fn clean_files(conn: &Connection, handler: Arc<AtomicBool>) -> Result<()> {
-    let files = db::init_clean_files(conn)?;
+    let files = db::get_files(conn)?;
 
     #[cfg(not(test))]
     let spinner = ProgressBar::with_draw_target(None, ProgressDrawTarget::stdout_with_hz(60))
@@ -247,13 +1119,15 @@ pub(crate) fn clean_files(conn: &Connection, handler: Arc<AtomicBool>) -> Result
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flac::EncoderBackend;
 
     #[test]
     fn test_index_lots_of_files() {
         let dbname = PathBuf::from("temp3.db");
         let handler = Arc::new(AtomicBool::new(true));
         let conn = db::init_connection(Some(&dbname)).unwrap();
-        index_files_recursively(Path::new("./testfiles"), &conn, handler).unwrap();
+        let vendor = EncoderBackend::default().vendor().unwrap();
+        index_files_recursively(Path::new("./testfiles"), &conn, handler, false, &vendor).unwrap();
         std::fs::remove_file(dbname).unwrap();
     }
 
@@ -271,13 +1145,13 @@ mod tests {
         std::fs::copy("./samples/32bit.flac", "./samples/nonexisting.flac").unwrap();
         for file in filenames {
             let filename = PathBuf::from(file);
-            db::insert_file(&conn, &filename).unwrap();
+            db::insert_file(&conn, &filename, "").unwrap();
         }
 
         std::fs::remove_file("./samples/nonexisting.flac").unwrap();
 
         clean_files(&conn, handler).unwrap();
-        let counter = db::init_clean_files(&conn).unwrap().len();
+        let counter = db::get_files(&conn).unwrap().len();
         std::fs::remove_file(dbname).unwrap();
         assert!(counter == 3)
     }
@@ -288,11 +1162,117 @@ mod tests {
         let handler = Arc::new(AtomicBool::new(true));
         let conn = db::init_connection(Some(&dbname)).unwrap();
         let temp = handler.clone();
-        index_files_recursively(Path::new("./testfiles"), &conn, temp).unwrap();
+        let vendor = EncoderBackend::default().vendor().unwrap();
+        index_files_recursively(Path::new("./testfiles"), &conn, temp, false, &vendor).unwrap();
         println!("\n{}", db::get_toencode_number(&conn).unwrap());
-        reencode_files(conn, handler, 4).unwrap();
+        let run = db::RunLock::acquire(Some(&dbname), false).unwrap();
+        reencode_files(
+            conn,
+            handler.clone(),
+            handler,
+            &RunOptions::default(),
+            run.owner(),
+        )
+        .unwrap();
+        drop(run);
         let conn = db::init_connection(Some(&dbname)).unwrap();
         println!("\n{}", db::get_toencode_number(&conn).unwrap());
         std::fs::remove_file(dbname).unwrap();
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reencode_hardlinks() {
+        let dbname = PathBuf::from("temp15.db");
+        let dir = Path::new("./samples/links");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let filenames = ["a.flac", "b.flac"].map(|name| dir.canonicalize().unwrap().join(name));
+        std::fs::copy("./samples/16bit.flac", &filenames[0]).unwrap();
+        std::fs::hard_link(&filenames[0], &filenames[1]).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let conn = db::init_connection(Some(&dbname)).unwrap();
+        index_files_recursively(dir, &conn, handler.clone(), false, "another vendor").unwrap();
+        let run = db::RunLock::acquire(Some(&dbname), false).unwrap();
+        reencode_files(
+            conn,
+            handler.clone(),
+            handler,
+            &RunOptions::default(),
+            run.owner(),
+        )
+        .unwrap();
+        drop(run);
+
+        let conn = db::init_connection(Some(&dbname)).unwrap();
+        let queued = db::get_toencode_number(&conn).unwrap();
+        let groups = db::get_linked_groups(&conn).unwrap();
+        let ids = filenames
+            .each_ref()
+            .map(|file| db::file_id(&file.metadata().unwrap()));
+        let vendor = crate::flac::get_vendor(&filenames[1]);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(queued == 0 && groups.len() == 1 && ids[0] == ids[1]);
+        assert!(vendor.unwrap() == EncoderBackend::default().vendor().unwrap());
+    }
+
+    #[test]
+    fn test_reencode_target_vendor() {
+        let dbname = PathBuf::from("temp17.db");
+        let name = Path::new("./samples/target.flac");
+        std::fs::copy("./samples/16bit.flac", name).unwrap();
+        let name = name.canonicalize().unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let conn = db::init_connection(Some(&dbname)).unwrap();
+        db::insert_file(&conn, &name, "custom").unwrap();
+        let run = db::RunLock::acquire(Some(&dbname), false).unwrap();
+        let options = RunOptions {
+            encode: EncodeOptions {
+                vendor: Some("custom".to_string()),
+                ..EncodeOptions::default()
+            },
+            ..RunOptions::default()
+        };
+        reencode_files(conn, handler.clone(), handler, &options, run.owner()).unwrap();
+        drop(run);
+
+        let conn = db::init_connection(Some(&dbname)).unwrap();
+        let flagged = db::flag_vendor_mismatch(&conn, "custom").unwrap();
+        let vendor = crate::flac::get_vendor(&name);
+        std::fs::remove_file(&name).unwrap();
+        std::fs::remove_file(dbname).unwrap();
+
+        assert!(flagged == 0);
+        assert!(vendor.unwrap() == EncoderBackend::default().vendor().unwrap());
+    }
+
+    #[test]
+    fn test_limit_queue() {
+        let files = vec![
+            (PathBuf::from("a.flac"), Some(300)),
+            (PathBuf::from("b.flac"), Some(200)),
+            (PathBuf::from("c.flac"), Some(100)),
+        ];
+        let by_count = limit_queue(files.clone(), Some(Limit::Files(2)));
+        let by_bytes = limit_queue(files, Some(Limit::Bytes(400)));
+        assert!(by_count == [PathBuf::from("a.flac"), PathBuf::from("b.flac")]);
+        assert!(by_bytes == [PathBuf::from("a.flac"), PathBuf::from("c.flac")])
+    }
+
+    #[test]
+    fn test_run_budget() {
+        let options = RunOptions {
+            deadline: Some(Instant::now()),
+            ..RunOptions::default()
+        };
+        let budget = RunOptions {
+            max_bytes: Some(100),
+            ..RunOptions::default()
+        };
+        assert!(options.exhausted(0).is_some());
+        assert!(budget.exhausted(99).is_none());
+        assert!(budget.exhausted(100).is_some())
+    }
 }
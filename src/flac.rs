@@ -1,117 +1,987 @@
+use crate::foreign;
+use crate::ogg::{self, OggFlacReader};
+use crate::pcm::PcmReader;
+use crate::pictures::PicturePolicy;
+use crate::spectrum::{LossyReport, SpectrumAnalyzer};
+use crate::tags::TagPolicy;
+use crate::throttle::{Throttle, Throttled};
 use anyhow::{Result, anyhow};
+use clap::{ValueEnum, builder::PossibleValue};
+#[cfg(feature = "libflac")]
 use flac_bound::FlacEncoder;
 use flac_codec::{
-    decode::{Metadata, verify},
+    decode::{Metadata, Verified, verify_reader},
     *,
 };
 use std::{
-    path::Path,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    num::NonZero,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, sync_channel},
     },
+    thread::JoinHandle,
 };
 
-pub(crate) const CURRENT_VENDOR: &str = "reference libFLAC 1.5.0 20250211";
-const BADTAGS: [&str; 3] = ["encoded_by", "encodedby", "encoder"];
+/// Distance between seek points of a regenerated seektable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeekSpacing {
+    Seconds(NonZero<u8>),
+    Samples(NonZero<u64>),
+}
 
-fn encode_file(filename: &Path, handler: Arc<AtomicBool>) -> Result<bool> {
-    if verify(filename).is_err() {
-        return Err(anyhow!("corrupt file"));
-    };
+impl Default for SeekSpacing {
+    fn default() -> Self {
+        SeekSpacing::Seconds(NonZero::new(10).unwrap())
+    }
+}
 
-    let temp_name = filename.with_extension("tmp");
-    if temp_name.exists() {
-        std::fs::remove_file(&temp_name)?;
+/// How seektables are carried over to the reencoded file
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SeekTablePolicy {
+    pub(crate) spacing: SeekSpacing,
+    /// Also generate a seektable for files that had none
+    pub(crate) add_missing: bool,
+}
+
+/// Size of the padding block written after the reencoded metadata
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum PaddingPolicy {
+    /// Whatever the encoder writes
+    #[default]
+    Encoder,
+    Fixed(u32),
+    /// Percentage of the encoded file size
+    Percent(f64),
+    /// Same amount of padding as the original file
+    Keep,
+}
+
+impl PaddingPolicy {
+    fn size(&self, original: u64, encoded: u64) -> Option<metadata::BlockSize> {
+        let size = match self {
+            PaddingPolicy::Encoder => return None,
+            PaddingPolicy::Fixed(size) => u64::from(*size),
+            PaddingPolicy::Percent(percent) => (encoded as f64 * percent / 100.0) as u64,
+            PaddingPolicy::Keep => original,
+        };
+        // a padding block can't grow beyond its 24 bit size field
+        Some(size.min((1 << 24) - 1).try_into().unwrap())
     }
+}
 
-    let mut reader = decode::FlacSampleReader::open(filename)?;
+/// Saving a reencode must achieve for the original to be replaced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MinSaving {
+    Bytes(u64),
+    /// Percentage of the original file size
+    Percent(f64),
+}
 
-    let blocklist = reader.metadata();
+impl MinSaving {
+    fn met(&self, original: u64, encoded: u64) -> bool {
+        let saving = original as f64 - encoded as f64;
+        match self {
+            MinSaving::Bytes(bytes) => saving >= *bytes as f64,
+            MinSaving::Percent(percent) => saving >= original as f64 * percent / 100.0,
+        }
+    }
+}
 
-    let streaminfo = blocklist.streaminfo();
+/// What to do about low bits that are zero in every sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum DepthPolicy {
+    /// Encode at the depth declared by the source
+    #[default]
+    Keep,
+    /// Measure the true depth and record it
+    Detect,
+    /// Measure the true depth and encode at it
+    Reduce,
+}
 
-    let channels = streaminfo.channel_count() as u32;
+impl ValueEnum for DepthPolicy {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[DepthPolicy::Keep, DepthPolicy::Detect, DepthPolicy::Reduce]
+    }
 
-    let metadata = blocklist
-        .blocks()
-        .filter_map(|block| {
-            use metadata::Block;
-            use metadata::BlockRef::*;
-            match block {
-                SeekTable(table) => Some(Block::SeekTable(table.clone())),
-                Application(app) => Some(Block::Application(app.clone())),
-                Cuesheet(sheet) => Some(Block::Cuesheet(sheet.clone())),
-                Picture(picture) => Some(Block::Picture(picture.clone())),
-                VorbisComment(comments) => {
-                    let mut cloned = comments.clone();
-                    for tag in BADTAGS {
-                        cloned.remove(tag);
-                    }
-                    cloned.vendor_string = CURRENT_VENDOR.to_string();
-                    Some(Block::VorbisComment(cloned))
-                }
-                _ => None,
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            DepthPolicy::Keep => "keep",
+            DepthPolicy::Detect => "detect",
+            DepthPolicy::Reduce => "reduce",
+        }))
+    }
+}
+
+/// Container Ogg FLAC files are reencoded into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum OggPolicy {
+    /// Stay Ogg FLAC
+    #[default]
+    Keep,
+    /// Become native FLAC files, replacing the Ogg ones
+    Native,
+}
+
+impl ValueEnum for OggPolicy {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[OggPolicy::Keep, OggPolicy::Native]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(match self {
+            OggPolicy::Keep => "keep",
+            OggPolicy::Native => "native",
+        }))
+    }
+}
+
+/// Encoder that writes new files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EncoderBackend {
+    /// The system libFLAC, through `flac-bound`
+    #[cfg(feature = "libflac")]
+    Libflac,
+    /// The pure-Rust encoder of `flac-codec`
+    Rust,
+    /// A `flac` binary fed raw samples through a pipe
+    External(PathBuf),
+}
+
+impl Default for EncoderBackend {
+    fn default() -> Self {
+        #[cfg(feature = "libflac")]
+        return EncoderBackend::Libflac;
+        #[cfg(not(feature = "libflac"))]
+        return EncoderBackend::Rust;
+    }
+}
+
+/// Backends that can be picked by name, the external one is picked by its path
+const NAMED_BACKENDS: &[EncoderBackend] = &[
+    #[cfg(feature = "libflac")]
+    EncoderBackend::Libflac,
+    EncoderBackend::Rust,
+];
+
+impl ValueEnum for EncoderBackend {
+    fn value_variants<'a>() -> &'a [Self] {
+        NAMED_BACKENDS
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            #[cfg(feature = "libflac")]
+            EncoderBackend::Libflac => Some(PossibleValue::new("libflac")),
+            EncoderBackend::Rust => Some(PossibleValue::new("rust")),
+            EncoderBackend::External(_) => None,
+        }
+    }
+}
+
+impl EncoderBackend {
+    /// Vendor string of the files this backend writes.
+    ///
+    /// The external binary is asked by encoding a moment of silence.
+    pub(crate) fn vendor(&self) -> Result<String> {
+        match self {
+            #[cfg(feature = "libflac")]
+            EncoderBackend::Libflac => Ok(libflac_vendor()),
+            EncoderBackend::Rust => Ok(metadata::VorbisComment::default().vendor_string),
+            EncoderBackend::External(_) => {
+                let path = std::env::temp_dir()
+                    .join(format!("reencoder-vendor-{}.flac", std::process::id()));
+                let format = StreamFormat {
+                    channels: 1,
+                    bits_per_sample: 16,
+                    sample_rate: 44100,
+                    total_samples: Some(4096),
+                };
+                let vendor = self
+                    .create(&path, &format, &EncoderSettings::default())
+                    .and_then(|mut encoder| match encoder.process(&[0; 4096]) {
+                        Ok(()) => encoder.finish(),
+                        Err(error) => {
+                            encoder.abandon();
+                            Err(error)
+                        }
+                    });
+                let _ = std::fs::remove_file(&path);
+                vendor
             }
-        })
-        .collect::<Vec<metadata::Block>>();
+        }
+    }
+
+    /// Starts a file at `path` for a stream shaped like `format`, compressed with `settings`
+    fn create(
+        &self,
+        path: &Path,
+        format: &StreamFormat,
+        settings: &EncoderSettings,
+    ) -> Result<Box<dyn Encoder>> {
+        match self {
+            #[cfg(feature = "libflac")]
+            EncoderBackend::Libflac => {
+                Ok(Box::new(LibflacEncoder::create(path, format, settings)?))
+            }
+            EncoderBackend::Rust => Ok(Box::new(RustEncoder::create(path, format, settings)?)),
+            EncoderBackend::External(binary) => Ok(Box::new(ExternalEncoder::create(
+                binary, path, format, settings,
+            )?)),
+        }
+    }
+}
+
+/// Compression settings for one encode, written like `8`, `8e` or `8:tukey(5e-1);partial_tukey(2)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EncoderSettings {
+    /// Compression level from 0 to 8
+    pub(crate) level: u32,
+    /// Try every model order instead of estimating the best one
+    pub(crate) exhaustive: bool,
+    /// Apodization functions in the syntax of `flac -A`, replacing the level's
+    pub(crate) apodization: Option<String>,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            level: 8,
+            exhaustive: false,
+            apodization: None,
+        }
+    }
+}
+
+impl std::fmt::Display for EncoderSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.level)?;
+        if self.exhaustive {
+            write!(f, "e")?;
+        }
+        if let Some(apodization) = &self.apodization {
+            write!(f, ":{apodization}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-file encoding settings shared by all reencoding threads
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EncodeOptions {
+    pub(crate) read_limit: Option<Arc<Throttle>>,
+    pub(crate) write_limit: Option<Arc<Throttle>>,
+    pub(crate) tags: TagPolicy,
+    pub(crate) seektable: SeekTablePolicy,
+    pub(crate) padding: PaddingPolicy,
+    pub(crate) pictures: PicturePolicy,
+    pub(crate) depth: DepthPolicy,
+    /// Reencode stereo files with identical channels as mono
+    pub(crate) collapse_dual_mono: bool,
+    pub(crate) ogg: OggPolicy,
+    pub(crate) encoder: EncoderBackend,
+    /// Settings to encode each file with in parallel, keeping the smallest result.
+    /// Empty means a single encode with the default settings.
+    pub(crate) trials: Vec<EncoderSettings>,
+    /// Keep the original unless reencoding saves at least this much.
+    /// Only applies to files that need nothing but a new vendor string.
+    pub(crate) min_saving: Option<MinSaving>,
+    /// Give originals kept for saving too little the encoder's vendor string
+    pub(crate) update_vendor: bool,
+    /// Vendor string that counts as done instead of the encoder's own.
+    /// New files still get the encoder's.
+    pub(crate) vendor: Option<String>,
+}
+
+/// What happened to a file handed to [`handle_encode`]
+#[derive(Debug, Default)]
+pub(crate) struct Outcome {
+    /// Encoding was abandoned and the original left untouched
+    pub(crate) aborted: bool,
+    /// Things worth telling the user about
+    pub(crate) notes: Vec<String>,
+    /// Bits actually used by the audio, if measured
+    pub(crate) true_depth: Option<u32>,
+    /// Whether both channels of a stereo file are identical, `None` for other layouts
+    pub(crate) dual_mono: Option<bool>,
+    /// New path of a file that was moved to another container
+    pub(crate) renamed: Option<PathBuf>,
+    /// Target vendor the kept original counts as done for, reencoding having saved too little
+    pub(crate) kept: Option<String>,
+    /// Settings of the trial that produced the smallest file, when any were given
+    pub(crate) settings: Option<EncoderSettings>,
+}
+
+impl Outcome {
+    fn aborted() -> Self {
+        Outcome {
+            aborted: true,
+            ..Outcome::default()
+        }
+    }
+}
+
+fn open_throttled(filename: &Path, options: &EncodeOptions) -> Result<BufReader<Throttled<File>>> {
+    Ok(BufReader::new(Throttled::new(
+        File::open(filename)?,
+        options.read_limit.clone(),
+    )))
+}
+
+/// Opens the native FLAC stream of `filename`, unwrapping it from Ogg or foreign tags if needed
+fn open_stream(filename: &Path, options: &EncodeOptions) -> Result<Box<dyn Read>> {
+    open_scanned_stream(filename, options, foreign::scan(filename)?.as_ref())
+}
+
+/// Like [`open_stream`], for a file whose foreign tags were already scanned
+fn open_scanned_stream(
+    filename: &Path,
+    options: &EncodeOptions,
+    foreign: Option<&foreign::ForeignTags>,
+) -> Result<Box<dyn Read>> {
+    let mut file = open_throttled(filename, options)?;
+    if ogg::is_ogg(filename) {
+        Ok(Box::new(OggFlacReader::new(file)?))
+    } else if let Some(tags) = foreign {
+        file.seek(SeekFrom::Start(tags.start))?;
+        Ok(Box::new(file.take(tags.end - tags.start)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Builds a seektable for the frames actually present in `filename`
+fn build_seektable(filename: &Path, spacing: SeekSpacing) -> Result<metadata::SeekTable> {
+    let interval = match spacing {
+        SeekSpacing::Seconds(seconds) => encode::SeekTableInterval::Seconds(seconds),
+        SeekSpacing::Samples(samples) => {
+            let block_size = u64::from(metadata::info(filename)?.maximum_block_size.max(1));
+            let frames = (samples.get() / block_size).max(1);
+            encode::SeekTableInterval::Frames(NonZero::new(frames as usize).unwrap())
+        }
+    };
+    Ok(encode::generate_seektable(
+        BufReader::new(File::open(filename)?),
+        interval,
+    )?)
+}
+
+/// Reader that fails as soon as `handler` is cleared, so long reads can be abandoned
+struct Abortable<R> {
+    inner: R,
+    handler: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for Abortable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.handler.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("aborted"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Temporary file next to `filename`, named after all of it so `x.oga` and `x.flac` don't share one
+pub(crate) fn temp_path(filename: &Path, extension: &str) -> PathBuf {
+    let mut name = filename.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Decodes a whole stream, failing on bad frame CRCs and on samples not matching the MD5
+fn verify_stream(reader: impl Read) -> Result<()> {
+    match verify_reader(reader)? {
+        Verified::MD5Mismatch => Err(anyhow!("MD5 mismatch")),
+        Verified::MD5Match | Verified::NoMD5 => Ok(()),
+    }
+}
+
+/// Decodes `filename` to check every frame CRC and the samples against the MD5.
+///
+/// Returns `false` if `handler` was cleared before the whole stream was read.
+pub(crate) fn audit_file(filename: &Path, handler: Arc<AtomicBool>) -> Result<bool> {
+    let verified = verify_stream(Abortable {
+        inner: open_stream(filename, &EncodeOptions::default())?,
+        handler: handler.clone(),
+    });
+    if !handler.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    verified.map(|_| true)
+}
+
+/// Properties of the decoded audio found by [`analyze`]
+struct Analysis {
+    /// Bits per sample left after dropping low bits that are zero in every sample
+    true_depth: u32,
+    /// Stereo with bit-identical channels
+    dual_mono: bool,
+}
 
-    let mut encoder = if let Some(encoder) = FlacEncoder::new() {
-        if let Ok(encoder) = {
-            let mut encoder = encoder
-                .channels(streaminfo.channel_count() as u32)
-                .bits_per_sample(streaminfo.bits_per_sample())
-                .sample_rate(streaminfo.sample_rate())
-                .compression_level(8)
-                .verify(false);
-            if let Some(size) = reader.total_samples() {
-                encoder = encoder.total_samples_estimate(size)
-            }
-            encoder.init_file(&temp_name)
-        } {
-            encoder
-        } else {
+/// Reads the whole stream once to find wasted low bits and identical stereo channels.
+///
+/// Returns `None` if `handler` was cleared before the whole stream was read.
+fn analyze(
+    filename: &Path,
+    handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+) -> Result<Option<Analysis>> {
+    let mut reader = decode::FlacSampleReader::new(Abortable {
+        inner: open_stream(filename, options)?,
+        handler: handler.clone(),
+    })?;
+    let streaminfo = reader.metadata().streaminfo();
+    let bits = streaminfo.bits_per_sample();
+    let mut dual_mono = streaminfo.channel_count() == 2;
+
+    let mut mask = 0;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(_) if !handler.load(Ordering::SeqCst) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        if buf.is_empty() {
+            break;
+        }
+        mask = buf.iter().fold(mask, |mask, sample| mask | sample);
+        dual_mono = dual_mono && buf.chunks_exact(2).all(|pair| pair[0] == pair[1]);
+        // the lowest bit is in use and the channels differ, so there is nothing left to find
+        if mask & 1 != 0 && !dual_mono {
+            break;
+        }
+        let length = buf.len();
+        reader.consume(length);
+    }
+
+    // digital silence has no meaningful depth
+    let wasted = if mask == 0 { 0 } else { mask.trailing_zeros() };
+    Ok(Some(Analysis {
+        // libFLAC can't encode below 4 bits per sample
+        true_depth: bits.saturating_sub(wasted).max(4),
+        dual_mono,
+    }))
+}
+
+/// Looks for signs of a lossy codec in the decoded audio, leaving the file untouched.
+///
+/// Returns `None` if `handler` was cleared before the whole stream was read.
+pub(crate) fn detect_lossy(
+    filename: &Path,
+    handler: Arc<AtomicBool>,
+) -> Result<Option<LossyReport>> {
+    let mut reader = decode::FlacSampleReader::new(Abortable {
+        inner: open_stream(filename, &EncodeOptions::default())?,
+        handler: handler.clone(),
+    })?;
+    let streaminfo = reader.metadata().streaminfo();
+    let sample_rate = streaminfo.sample_rate();
+    let mut analyzer = SpectrumAnalyzer::new(
+        sample_rate,
+        streaminfo.channel_count() as u32,
+        streaminfo.bits_per_sample(),
+        reader.total_samples(),
+    );
+
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(_) if !handler.load(Ordering::SeqCst) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        if buf.is_empty() {
+            break;
+        }
+        analyzer.feed(buf);
+        let length = buf.len();
+        reader.consume(length);
+    }
+
+    // audio too short to analyse shows no sign of a lossy codec, and isn't retried
+    Ok(Some(analyzer.finish().unwrap_or(LossyReport {
+        cutoff: sample_rate / 2,
+        holes: 0.0,
+        confidence: 0.0,
+    })))
+}
+
+/// Interleaved samples the encoder can be fed from
+trait SampleSource {
+    fn fill_buf(&mut self) -> Result<&[i32]>;
+    fn consume(&mut self, amount: usize);
+}
+
+impl<R: Read> SampleSource for decode::FlacSampleReader<R> {
+    fn fill_buf(&mut self) -> Result<&[i32]> {
+        Ok(decode::FlacSampleReader::fill_buf(self)?)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        decode::FlacSampleReader::consume(self, amount)
+    }
+}
+
+impl<R: Read + Seek> SampleSource for PcmReader<R> {
+    fn fill_buf(&mut self) -> Result<&[i32]> {
+        PcmReader::fill_buf(self)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        PcmReader::consume(self, amount)
+    }
+}
+
+/// Shape of the stream handed to the encoder
+struct StreamFormat {
+    channels: u32,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    total_samples: Option<u64>,
+}
+
+/// A FLAC encoder writing one new file, created through [`EncoderBackend::create`]
+trait Encoder {
+    /// Feeds interleaved samples, whole frames only
+    fn process(&mut self, samples: &[i32]) -> Result<()>;
+
+    /// Completes the file, returning the vendor string it was written with
+    fn finish(self: Box<Self>) -> Result<String>;
+
+    /// Gives up on the file, which the caller removes
+    fn abandon(self: Box<Self>) {
+        let _ = self.finish();
+    }
+}
+
+/// Vendor string of the libFLAC linked in, which it writes into every file
+#[cfg(feature = "libflac")]
+fn libflac_vendor() -> String {
+    // SAFETY: libFLAC points this at a static, null terminated string and never changes it
+    unsafe { std::ffi::CStr::from_ptr(libflac_sys::FLAC__VENDOR_STRING) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(feature = "libflac")]
+struct LibflacEncoder {
+    encoder: FlacEncoder<'static>,
+    channels: u32,
+}
+
+#[cfg(feature = "libflac")]
+impl LibflacEncoder {
+    fn create(path: &Path, format: &StreamFormat, settings: &EncoderSettings) -> Result<Self> {
+        let Some(encoder) = FlacEncoder::new() else {
             return Err(anyhow!("failed to create encoder"));
+        };
+        // the level sets the apodization too, so a custom one has to come after it
+        let mut encoder = encoder
+            .channels(format.channels)
+            .bits_per_sample(format.bits_per_sample)
+            .sample_rate(format.sample_rate)
+            .compression_level(settings.level)
+            .do_exhaustive_model_search(settings.exhaustive)
+            .verify(false);
+        if let Some(apodization) = &settings.apodization {
+            encoder = encoder.apodization(&std::ffi::CString::new(apodization.as_str())?);
+        }
+        if let Some(size) = format.total_samples {
+            encoder = encoder.total_samples_estimate(size)
+        }
+        match encoder.init_file(&path) {
+            Ok(encoder) => Ok(LibflacEncoder {
+                encoder,
+                channels: format.channels,
+            }),
+            Err(_) => Err(anyhow!("failed to create encoder")),
+        }
+    }
+}
+
+#[cfg(feature = "libflac")]
+impl Encoder for LibflacEncoder {
+    fn process(&mut self, samples: &[i32]) -> Result<()> {
+        let frames = samples.len() as u32 / self.channels;
+        if self.encoder.process_interleaved(samples, frames).is_err() {
+            return Err(anyhow!(
+                "Error while processing samples:\t{:?}",
+                self.encoder.state()
+            ));
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        if let Err(enc) = self.encoder.finish() {
+            return Err(anyhow!("Encoding failed:\t{:?}", enc.state()));
+        }
+        Ok(libflac_vendor())
+    }
+}
+
+struct RustEncoder(encode::FlacSampleWriter<BufWriter<File>>);
+
+impl RustEncoder {
+    fn create(path: &Path, format: &StreamFormat, settings: &EncoderSettings) -> Result<Self> {
+        if *settings != EncoderSettings::default() {
+            return Err(anyhow!(
+                "the rust encoder can't encode with settings {settings}"
+            ));
+        }
+        // metadata is written afterwards by finish_metadata, like with libFLAC
+        Ok(RustEncoder(encode::FlacSampleWriter::create(
+            path,
+            encode::Options::best()
+                .no_padding()
+                .no_seektable()
+                .overwrite(),
+            format.sample_rate,
+            format.bits_per_sample,
+            u8::try_from(format.channels)?,
+            None,
+        )?))
+    }
+}
+
+impl Encoder for RustEncoder {
+    fn process(&mut self, samples: &[i32]) -> Result<()> {
+        Ok(self.0.write(samples)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        self.0.finalize()?;
+        Ok(metadata::VorbisComment::default().vendor_string)
+    }
+}
+
+struct ExternalEncoder {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    /// Collects stderr, so a chatty encoder never blocks on a full pipe
+    stderr: JoinHandle<Vec<u8>>,
+    path: PathBuf,
+    /// Bytes per raw sample
+    width: usize,
+    buffer: Vec<u8>,
+}
+
+impl ExternalEncoder {
+    fn create(
+        binary: &Path,
+        path: &Path,
+        format: &StreamFormat,
+        settings: &EncoderSettings,
+    ) -> Result<Self> {
+        let mut command = Command::new(binary);
+        command.arg(format!("-{}", settings.level));
+        if settings.exhaustive {
+            command.arg("-e");
+        }
+        if let Some(apodization) = &settings.apodization {
+            command.arg("-A").arg(apodization);
+        }
+        let mut child = command
+            .args(["--silent", "--force", "--no-padding", "--no-seektable"])
+            .args(["--force-raw-format", "--endian=little", "--sign=signed"])
+            .arg(format!("--channels={}", format.channels))
+            .arg(format!("--bps={}", format.bits_per_sample))
+            .arg(format!("--sample-rate={}", format.sample_rate))
+            .arg("-o")
+            .arg(path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| anyhow!("failed to run {}: {error}", binary.to_string_lossy()))?;
+        let stdin = BufWriter::new(child.stdin.take().unwrap());
+        let mut pipe = child.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = pipe.read_to_end(&mut output);
+            output
+        });
+        Ok(ExternalEncoder {
+            child,
+            stdin,
+            stderr,
+            path: path.to_path_buf(),
+            width: format.bits_per_sample.div_ceil(8) as usize,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl Encoder for ExternalEncoder {
+    fn process(&mut self, samples: &[i32]) -> Result<()> {
+        self.buffer.clear();
+        for sample in samples {
+            self.buffer
+                .extend_from_slice(&sample.to_le_bytes()[..self.width]);
         }
+        Ok(self.stdin.write_all(&self.buffer)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        let ExternalEncoder {
+            mut child,
+            mut stdin,
+            stderr,
+            path,
+            ..
+        } = *self;
+        let flushed = stdin.flush();
+        // closing the pipe ends the stream
+        drop(stdin);
+        let status = child.wait()?;
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(anyhow!(
+                "Encoding failed:\t{}",
+                String::from_utf8_lossy(&stderr).trim()
+            ));
+        }
+        flushed?;
+        match metadata::block::<_, metadata::VorbisComment>(&path)? {
+            Some(comment) => Ok(comment.vendor_string),
+            None => Err(anyhow!("Vendor string not found")),
+        }
+    }
+
+    fn abandon(mut self: Box<Self>) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = self.stderr.join();
+    }
+}
+
+/// What [`encode_samples`] produced
+struct Encoded {
+    /// Whether a stereo stream that wasn't collapsed had identical channels
+    dual_mono: bool,
+    /// Vendor string of the encoder
+    vendor: String,
+    /// Settings of the trial that won, when any were given
+    settings: Option<EncoderSettings>,
+}
+
+/// Encodes the chunks arriving on `chunks` into `path`.
+///
+/// A `None` chunk completes the file, while the channel closing without one abandons it.
+fn run_trial(
+    backend: &EncoderBackend,
+    path: &Path,
+    format: &StreamFormat,
+    settings: &EncoderSettings,
+    chunks: Receiver<Option<Arc<[i32]>>>,
+) -> Result<Option<String>> {
+    let mut encoder = backend.create(path, format, settings)?;
+    while let Ok(chunk) = chunks.recv() {
+        match chunk {
+            Some(samples) => {
+                if let Err(error) = encoder.process(&samples) {
+                    encoder.abandon();
+                    return Err(error);
+                }
+            }
+            None => return encoder.finish().map(Some),
+        }
+    }
+    encoder.abandon();
+    Ok(None)
+}
+
+/// Encodes everything `source` yields into `temp_name`.
+///
+/// Samples are shifted right by `shift` and, with `collapse`, cut down to the first channel.
+/// With several trials in `options`, each gets its own encoder fed from this one decode, and
+/// the smallest result that verifies ends up in `temp_name`.
+/// Returns `None` if `handler` was cleared midway.
+fn encode_samples(
+    source: &mut impl SampleSource,
+    format: &StreamFormat,
+    shift: u32,
+    collapse: bool,
+    temp_name: &Path,
+    handler: &AtomicBool,
+    options: &EncodeOptions,
+) -> Result<Option<Encoded>> {
+    let channels = format.channels;
+    let encoded_format = StreamFormat {
+        channels: if collapse { 1 } else { channels },
+        bits_per_sample: format.bits_per_sample - shift,
+        sample_rate: format.sample_rate,
+        total_samples: format.total_samples,
+    };
+    let trials = if options.trials.is_empty() {
+        vec![EncoderSettings::default()]
+    } else {
+        options.trials.clone()
+    };
+    let paths = if trials.len() == 1 {
+        vec![temp_name.to_path_buf()]
     } else {
-        return Err(anyhow!("failed to create encoder"));
+        (0..trials.len())
+            .map(|index| temp_name.with_extension(format!("{index}.tmp")))
+            .collect()
     };
 
-    while handler.load(Ordering::SeqCst) {
-        match reader.fill_buf() {
-            Ok(buf) => {
-                if !buf.is_empty() {
-                    let length = buf.len();
-                    if encoder
-                        .process_interleaved(buf, length as u32 / channels)
-                        .is_err()
-                    {
-                        return Err(anyhow!(
-                            "Error while processing samples:\t{:?}",
-                            encoder.state()
-                        ));
-                    };
-
-                    reader.consume(length);
+    let mut dual_mono = channels == 2 && !collapse;
+    let (fed, results) = std::thread::scope(|s| {
+        let mut senders = Vec::new();
+        let mut workers = Vec::new();
+        for (settings, path) in trials.iter().zip(&paths) {
+            let (sender, receiver) = sync_channel(4);
+            senders.push(sender);
+            let format = &encoded_format;
+            workers.push(
+                s.spawn(move || run_trial(&options.encoder, path, format, settings, receiver)),
+            );
+        }
+
+        let mut feed = || -> Result<bool> {
+            let mut written = 0;
+            while handler.load(Ordering::SeqCst) {
+                if let Some(throttle) = &options.write_limit {
+                    // encoders write through their own file handles, so pace them by the growth of the output
+                    let length = paths
+                        .iter()
+                        .map(|path| path.metadata().map(|meta| meta.len()).unwrap_or(0))
+                        .sum::<u64>()
+                        .max(written);
+                    throttle.consume(length - written);
+                    written = length;
+                }
+                let buf = source.fill_buf()?;
+                if buf.is_empty() {
+                    return Ok(true);
+                }
+                let length = buf.len();
+                let samples: Arc<[i32]> = if collapse {
+                    buf.iter()
+                        .step_by(2)
+                        .map(|sample| sample >> shift)
+                        .collect()
+                } else if shift > 0 {
+                    buf.iter().map(|sample| sample >> shift).collect()
                 } else {
-                    break;
+                    Arc::from(buf)
+                };
+                if dual_mono {
+                    dual_mono = buf.chunks_exact(2).all(|pair| pair[0] == pair[1]);
+                }
+                for sender in &senders {
+                    // a trial only hangs up when it failed, which joining it reports
+                    if sender.send(Some(samples.clone())).is_err() {
+                        return Ok(false);
+                    }
                 }
+                source.consume(length);
+            }
+            Ok(false)
+        };
+        let fed = feed();
+        if let Ok(true) = fed {
+            for sender in &senders {
+                let _ = sender.send(None);
             }
-            Err(error) => return Err(error.into()),
         }
+        drop(senders);
+        let results = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Result<Vec<_>>>();
+        (fed, results)
+    });
+
+    let vendors = match (fed, results) {
+        (Ok(true), Ok(vendors)) => vendors.into_iter().flatten().collect::<Vec<_>>(),
+        (fed, results) => {
+            for path in &paths {
+                let _ = std::fs::remove_file(path);
+            }
+            results?;
+            fed?;
+            return Ok(None);
+        }
+    };
+
+    if trials.len() == 1 {
+        return Ok(Some(Encoded {
+            dual_mono,
+            vendor: vendors.into_iter().next().unwrap(),
+            // a lone --trial still names the settings used
+            settings: options.trials.first().cloned(),
+        }));
     }
 
-    if !handler.load(Ordering::SeqCst) {
-        let _ = encoder.finish();
-        std::fs::remove_file(temp_name)?;
-        return Ok(true);
+    let mut ranked = paths
+        .iter()
+        .map(|path| Ok(path.metadata()?.len()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|(_, size)| *size);
+    // the encoders hashed the samples they were given, so verifying proves a trial bit-exact
+    let winner = ranked.into_iter().map(|(index, _)| index).find(|index| {
+        File::open(&paths[*index]).is_ok_and(|file| verify_stream(BufReader::new(file)).is_ok())
+    });
+    for (index, path) in paths.iter().enumerate() {
+        if Some(index) != winner {
+            std::fs::remove_file(path)?;
+        }
     }
+    let Some(winner) = winner else {
+        return Err(anyhow!("no trial encode verified"));
+    };
+    std::fs::rename(&paths[winner], temp_name)?;
 
-    if let Err(enc) = encoder.finish() {
-        return Err(anyhow!("Encoding failed:\t{:?}", enc.state()));
+    Ok(Some(Encoded {
+        dual_mono,
+        vendor: vendors[winner].clone(),
+        settings: Some(trials[winner].clone()),
+    }))
+}
+
+/// Writes `metadata` into the freshly encoded `temp_name`, along with a new seektable if
+/// `seektable` is set and padding sized per policy. The comment gets the encoder's `vendor`.
+fn finish_metadata(
+    temp_name: &Path,
+    mut metadata: Vec<metadata::Block>,
+    seektable: bool,
+    original_padding: u64,
+    vendor: &str,
+    options: &EncodeOptions,
+) -> Result<()> {
+    if seektable {
+        metadata.push(metadata::Block::SeekTable(build_seektable(
+            temp_name,
+            options.seektable.spacing,
+        )?));
     }
 
-    metadata::update(&temp_name, |blocklist| {
+    // an empty padding block is just four bytes of header, e.g. keeping no padding
+    if let Some(size) = options
+        .padding
+        .size(original_padding, temp_name.metadata()?.len())
+        && u32::from(size) > 0
+    {
+        metadata.push(metadata::Block::Padding(metadata::Padding { size }));
+    }
+
+    metadata::update(temp_name, |blocklist| {
+        // without the encoder's padding the metadata grows by more than our padding block,
+        // so it can't absorb the growth and the file is rebuilt with the block as is
+        if options.padding != PaddingPolicy::Encoder {
+            blocklist.remove::<metadata::Padding>();
+        }
         for block in metadata {
             use metadata::Block::*;
             match block {
@@ -130,29 +1000,509 @@ fn encode_file(filename: &Path, handler: Arc<AtomicBool>) -> Result<bool> {
                 SeekTable(b) => {
                     let _ = blocklist.insert(b);
                 }
+                Padding(b) => {
+                    let _ = blocklist.insert(b);
+                }
                 _ => {}
             }
         }
+        // files without tags still carry the vendor
+        blocklist.update::<metadata::VorbisComment>(|comment| {
+            comment.vendor_string = vendor.to_string()
+        });
         Ok::<(), flac_codec::Error>(())
     })?;
 
-    std::fs::rename(&temp_name, filename)?;
+    Ok(())
+}
+
+fn encode_file(
+    filename: &Path,
+    handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+) -> Result<Outcome> {
+    let verified = verify_stream(Abortable {
+        inner: open_stream(filename, options)?,
+        handler: handler.clone(),
+    });
+    if !handler.load(Ordering::SeqCst) {
+        return Ok(Outcome::aborted());
+    }
+    if verified.is_err() {
+        return Err(anyhow!("corrupt file"));
+    };
+
+    let temp_name = temp_path(filename, "tmp");
+    if temp_name.exists() {
+        std::fs::remove_file(&temp_name)?;
+    }
+    let native_target = (ogg::is_ogg(filename) && options.ogg == OggPolicy::Native)
+        .then(|| filename.with_extension("flac"));
+    if let Some(target) = &native_target
+        && target.exists()
+    {
+        return Err(anyhow!("{} already exists", target.to_string_lossy()));
+    }
+
+    let analysis = if options.depth == DepthPolicy::Keep && !options.collapse_dual_mono {
+        None
+    } else {
+        match analyze(filename, handler.clone(), options)? {
+            Some(analysis) => Some(analysis),
+            None => return Ok(Outcome::aborted()),
+        }
+    };
+    let true_depth = analysis
+        .as_ref()
+        .filter(|_| options.depth != DepthPolicy::Keep)
+        .map(|analysis| analysis.true_depth);
+    let collapse =
+        options.collapse_dual_mono && analysis.as_ref().is_some_and(|analysis| analysis.dual_mono);
+
+    let mut reader = decode::FlacSampleReader::new(open_stream(filename, options)?)?;
+
+    let blocklist = reader.metadata();
+
+    let streaminfo = blocklist.streaminfo();
+
+    let channels = streaminfo.channel_count() as u32;
+    let original_md5 = streaminfo.md5;
+    let had_md5 = original_md5.is_some();
+
+    let mut notes = Vec::new();
+    if !had_md5 {
+        notes.push("computed the missing MD5".to_string());
+    }
+    if collapse {
+        notes.push("collapsed dual mono to mono".to_string());
+    }
+    let bits = streaminfo.bits_per_sample();
+    // samples are shifted right by this much, which is exact since the dropped bits are all zero
+    let shift = match true_depth {
+        Some(depth) if options.depth == DepthPolicy::Reduce && depth < bits => {
+            notes.push(format!("reduced from {bits} to {depth} bits"));
+            bits - depth
+        }
+        _ => 0,
+    };
+
+    // the old seek points refer to the old encoding, so they are rebuilt after encoding
+    let had_seektable = blocklist.has::<metadata::SeekTable>();
+    let original_padding = blocklist
+        .get_all::<metadata::Padding>()
+        .map(|padding| u64::from(u32::from(padding.size)))
+        .sum();
+
+    let mut comment = blocklist.get::<metadata::VorbisComment>().cloned();
+    let foreign = foreign::scan(filename)?;
+    if let Some(foreign) = &foreign {
+        notes.push(foreign.migrate(comment.get_or_insert_default()));
+    }
+    let mut comment = comment.map(|mut comment| {
+        options.tags.apply(&mut comment);
+        metadata::Block::VorbisComment(comment)
+    });
+
+    let mut metadata = blocklist
+        .blocks()
+        .filter_map(|block| {
+            use metadata::Block;
+            use metadata::BlockRef::*;
+            match block {
+                Application(app) => Some(Block::Application(app.clone())),
+                Cuesheet(sheet) => Some(Block::Cuesheet(sheet.clone())),
+                VorbisComment(_) => comment.take(),
+                _ => None,
+            }
+        })
+        .collect::<Vec<metadata::Block>>();
+    // files whose only tags were foreign ones had no comment block to take the fields
+    metadata.extend(comment);
+
+    let original_pictures = blocklist.get_all::<metadata::Picture>().count();
+    let (pictures, picture_notes) = options.pictures.apply(
+        filename,
+        blocklist.get_all::<metadata::Picture>().cloned().collect(),
+    )?;
+    notes.extend(picture_notes);
+    let pictures_changed = pictures.len() != original_pictures;
+    metadata.extend(pictures.into_iter().map(metadata::Block::Picture));
+
+    let format = StreamFormat {
+        channels,
+        bits_per_sample: bits,
+        sample_rate: streaminfo.sample_rate(),
+        total_samples: reader.total_samples(),
+    };
+    let Some(Encoded {
+        dual_mono: identical,
+        vendor,
+        settings,
+    }) = encode_samples(
+        &mut reader,
+        &format,
+        shift,
+        collapse,
+        &temp_name,
+        &handler,
+        options,
+    )?
+    else {
+        return Ok(Outcome::aborted());
+    };
+    let dual_mono = collapse || identical;
+
+    finish_metadata(
+        &temp_name,
+        metadata,
+        had_seektable || options.seektable.add_missing,
+        original_padding,
+        &vendor,
+        options,
+    )?;
+
+    // the encoder hashes the samples it was fed, so an unchanged stream must keep its MD5
+    if shift == 0
+        && !collapse
+        && let Some(md5) = original_md5
+    {
+        if metadata::info(&temp_name)?.md5 != Some(md5) {
+            return Err(anyhow!(
+                "MD5 of the reencoded file doesn't match the original"
+            ));
+        }
+    } else if verify_stream(BufReader::new(File::open(&temp_name)?)).is_err() {
+        return Err(anyhow!("verification of the reencoded file failed"));
+    }
+
+    // keeping the original only makes sense if nothing but the vendor would change,
+    // a policy that drops pictures or resizes the padding has to be applied by reencoding
+    let vendor_only = native_target.is_none()
+        && foreign.is_none()
+        && shift == 0
+        && !collapse
+        && !pictures_changed
+        && matches!(
+            options.padding,
+            PaddingPolicy::Encoder | PaddingPolicy::Keep
+        );
+    if let Some(min_saving) = options.min_saving.filter(|_| vendor_only) {
+        let original = filename.metadata()?.len();
+        let encoded = temp_name.metadata()?.len();
+        if !min_saving.met(original, encoded) {
+            notes.push(format!(
+                "kept the original, reencoding saved {} bytes",
+                original as i64 - encoded as i64
+            ));
+            // the new file hashed the very same samples
+            let md5 = if had_md5 {
+                None
+            } else {
+                metadata::info(&temp_name)?.md5
+            };
+            std::fs::remove_file(&temp_name)?;
+            if md5.is_some() {
+                notes.push("wrote the missing MD5 into the original".to_string());
+            }
+            let vendor_update = options.update_vendor.then_some(vendor.as_str());
+            if vendor_update.is_some() || md5.is_some() {
+                update_kept(filename, vendor_update, md5)?;
+            }
+            return Ok(Outcome {
+                notes,
+                true_depth,
+                dual_mono: (channels == 2).then_some(dual_mono),
+                kept: Some(options.vendor.clone().unwrap_or(vendor)),
+                ..Outcome::default()
+            });
+        }
+    }
+
+    if let Some(target) = &native_target {
+        std::fs::rename(&temp_name, target)?;
+        std::fs::remove_file(filename)?;
+    } else if ogg::is_ogg(filename) {
+        let ogg_temp = temp_path(filename, "ogg.tmp");
+        ogg::remux(&temp_name, &ogg_temp)?;
+        std::fs::remove_file(&temp_name)?;
+        if verify_stream(OggFlacReader::new(BufReader::new(File::open(&ogg_temp)?))?).is_err() {
+            return Err(anyhow!("verification of the remuxed file failed"));
+        }
+        std::fs::rename(&ogg_temp, filename)?;
+    } else {
+        std::fs::rename(&temp_name, filename)?;
+    }
+
+    Ok(Outcome {
+        aborted: false,
+        notes,
+        true_depth,
+        dual_mono: (channels == 2).then_some(dual_mono),
+        renamed: native_target,
+        kept: None,
+        settings,
+    })
+}
+
+/// Encodes an uncompressed WAV, AIFF or W64 file into a FLAC file next to it.
+///
+/// The result is verified before it's moved into place, the source is left to the caller.
+fn convert_file(
+    source: &Path,
+    handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+) -> Result<Outcome> {
+    let target = source.with_extension("flac");
+    if target.exists() {
+        return Err(anyhow!("{} already exists", target.to_string_lossy()));
+    }
+    let temp_name = temp_path(source, "tmp");
+    if temp_name.exists() {
+        std::fs::remove_file(&temp_name)?;
+    }
+
+    let mut reader = PcmReader::new(open_throttled(source, options)?)?;
+    let format = StreamFormat {
+        channels: reader.format.channels,
+        bits_per_sample: reader.format.bits_per_sample,
+        sample_rate: reader.format.sample_rate,
+        total_samples: Some(reader.format.total_samples),
+    };
+    let Some(Encoded {
+        dual_mono,
+        vendor,
+        settings,
+    }) = encode_samples(
+        &mut reader,
+        &format,
+        0,
+        false,
+        &temp_name,
+        &handler,
+        options,
+    )?
+    else {
+        return Ok(Outcome::aborted());
+    };
+
+    let mut comment = metadata::VorbisComment {
+        fields: reader
+            .tags
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect(),
+        ..Default::default()
+    };
+    options.tags.apply(&mut comment);
+    finish_metadata(
+        &temp_name,
+        vec![metadata::Block::VorbisComment(comment)],
+        options.seektable.add_missing,
+        0,
+        &vendor,
+        options,
+    )?;
 
-    Ok(false)
+    // the encoder hashed the samples it was given, so this checks them against the new file
+    if verify_stream(BufReader::new(File::open(&temp_name)?)).is_err() {
+        return Err(anyhow!("verification of the new file failed"));
+    }
+    std::fs::rename(&temp_name, &target)?;
+
+    Ok(Outcome {
+        aborted: false,
+        notes: Vec::new(),
+        true_depth: None,
+        dual_mono: (format.channels == 2).then_some(dual_mono),
+        renamed: None,
+        kept: None,
+        settings,
+    })
 }
 
-pub(crate) fn handle_encode(filename: &Path, handler: Arc<AtomicBool>) -> Result<bool> {
-    match encode_file(filename, handler) {
+pub(crate) fn handle_convert(
+    source: &Path,
+    handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+) -> Result<Outcome> {
+    match convert_file(source, handler, options) {
         Err(error) => {
-            let _ = std::fs::remove_file(filename.with_extension("tmp"));
+            let _ = std::fs::remove_file(temp_path(source, "tmp"));
             Err(error)
         }
         Ok(res) => Ok(res),
     }
 }
 
+pub(crate) fn handle_encode(
+    filename: &Path,
+    handler: Arc<AtomicBool>,
+    options: &EncodeOptions,
+) -> Result<Outcome> {
+    match encode_file(filename, handler, options) {
+        Err(error) => {
+            let _ = std::fs::remove_file(temp_path(filename, "tmp"));
+            let _ = std::fs::remove_file(temp_path(filename, "ogg.tmp"));
+            Err(error)
+        }
+        Ok(res) => Ok(res),
+    }
+}
+
+/// Merges scattered padding into a single block, capped at the largest size a block can hold.
+///
+/// Returns `true` if anything changed.
+fn tidy_blocks(blocklist: &mut metadata::BlockList) -> bool {
+    if blocklist.get_all::<metadata::Padding>().count() < 2 {
+        return false;
+    }
+    let total = blocklist
+        .extract::<metadata::Padding>()
+        .map(|padding| u64::from(u32::from(padding.size)))
+        .sum::<u64>();
+    let size = total.min((1 << 24) - 1).try_into().unwrap();
+    blocklist.insert(metadata::Padding { size });
+    true
+}
+
+/// Runs `update` on the native FLAC stream of `filename`.
+///
+/// Ogg FLAC files and files wrapped in foreign tags go through a temporary native copy,
+/// which replaces the original if `update` reports a change. Foreign tags are always
+/// stripped, with their fields moved into the Vorbis comment.
+fn update_native<T>(
+    filename: &Path,
+    update: impl FnOnce(&Path) -> Result<(bool, T)>,
+) -> Result<(bool, T)> {
+    let is_ogg = ogg::is_ogg(filename);
+    let foreign = foreign::scan(filename)?;
+    if !is_ogg && foreign.is_none() {
+        return update(filename);
+    }
+    let temp_name = temp_path(filename, "tmp");
+    let ogg_temp = temp_path(filename, "ogg.tmp");
+    let result = (|| {
+        {
+            let mut writer = BufWriter::new(File::create(&temp_name)?);
+            std::io::copy(
+                &mut open_stream(filename, &EncodeOptions::default())?,
+                &mut writer,
+            )?;
+            writer.flush()?;
+        }
+        if let Some(foreign) = &foreign {
+            metadata::update(&temp_name, |blocklist| {
+                blocklist.update::<metadata::VorbisComment>(|comment| {
+                    foreign.migrate(comment);
+                });
+                Ok::<(), flac_codec::Error>(())
+            })?;
+        }
+        let mut result = update(&temp_name)?;
+        result.0 |= foreign.is_some();
+        // a false match on a foreign tag would have cut the audio short
+        if result.0 && verify_stream(BufReader::new(File::open(&temp_name)?)).is_err() {
+            return Err(anyhow!("verification of the rewritten file failed"));
+        }
+        if result.0 && is_ogg {
+            ogg::remux(&temp_name, &ogg_temp)?;
+            std::fs::rename(&ogg_temp, filename)?;
+        } else if result.0 {
+            std::fs::rename(&temp_name, filename)?;
+        }
+        Ok(result)
+    })();
+    let _ = std::fs::remove_file(&temp_name);
+    let _ = std::fs::remove_file(&ogg_temp);
+    result
+}
+
+/// Rewrites the vendor string and fills in a missing MD5 in place without touching the audio
+fn update_kept(filename: &Path, vendor: Option<&str>, md5: Option<[u8; 16]>) -> Result<()> {
+    update_native(filename, |native| {
+        metadata::update(native, |blocklist| {
+            if let Some(vendor) = vendor {
+                blocklist.update::<metadata::VorbisComment>(|comment| {
+                    comment.vendor_string = vendor.to_string()
+                });
+            }
+            if md5.is_some() {
+                blocklist.streaminfo_mut().md5 = md5;
+            }
+            Ok::<(), flac_codec::Error>(())
+        })?;
+        Ok((true, ()))
+    })?;
+    Ok(())
+}
+
+/// Applies the tag policy and metadata cleanup in place without touching the audio.
+///
+/// With `vendor`, the vendor string is rewritten as well.
+/// Returns `true` if the file was modified.
+pub(crate) fn retag_file(
+    filename: &Path,
+    policy: &TagPolicy,
+    vendor: Option<&str>,
+) -> Result<bool> {
+    let (changed, ()) = update_native(filename, |native| {
+        let mut changed = false;
+        metadata::update(native, |blocklist| {
+            if let Some(comment) = blocklist.get_mut::<metadata::VorbisComment>() {
+                changed = policy.apply(comment);
+                if let Some(vendor) = vendor
+                    && comment.vendor_string != vendor
+                {
+                    comment.vendor_string = vendor.to_string();
+                    changed = true;
+                }
+            }
+            changed |= tidy_blocks(blocklist);
+            Ok::<(), flac_codec::Error>(())
+        })?;
+        Ok((changed, ()))
+    })?;
+    Ok(changed)
+}
+
+/// Applies the picture policy in place without touching the audio.
+///
+/// Returns `true` if the file was modified, along with notes worth reporting.
+pub(crate) fn fix_pictures(filename: &Path, policy: &PicturePolicy) -> Result<(bool, Vec<String>)> {
+    update_native(filename, |native| {
+        let mut result = (false, Vec::new());
+        metadata::update(native, |blocklist| {
+            let pictures = blocklist.extract::<metadata::Picture>().collect::<Vec<_>>();
+            let count = pictures.len();
+            // covers are extracted next to the real file, not the temporary copy
+            let (kept, notes) = policy.apply(filename, pictures)?;
+            result = (kept.len() != count, notes);
+            for picture in kept {
+                blocklist.insert(picture);
+            }
+            Ok::<(), anyhow::Error>(())
+        })?;
+        Ok(result)
+    })
+}
+
+pub(crate) fn get_streaminfo(file: &Path) -> Result<metadata::Streaminfo> {
+    let blocklist = metadata::BlockList::read(open_stream(file, &EncodeOptions::default())?)?;
+    Ok(blocklist.streaminfo().clone())
+}
+
+/// Whether the STREAMINFO of `file` holds an MD5 of the audio, rather than all zeroes
+pub(crate) fn has_md5(file: &Path) -> Result<bool> {
+    Ok(get_streaminfo(file)?.md5.is_some())
+}
+
+#[cfg(test)]
 pub(crate) fn get_vendor(file: &Path) -> Result<String> {
-    let blocklist = metadata::BlockList::open(file)?;
+    let blocklist = metadata::BlockList::read(open_stream(file, &EncodeOptions::default())?)?;
+    vendor_of(&blocklist)
+}
+
+pub(crate) fn vendor_of(blocklist: &metadata::BlockList) -> Result<String> {
     if let Some(data) = blocklist.get::<metadata::VorbisComment>() {
         Ok(data.vendor_string.to_owned())
     } else {
@@ -160,18 +1510,546 @@ pub(crate) fn get_vendor(file: &Path) -> Result<String> {
     }
 }
 
+/// Reads the metadata of `file` and the foreign tags around its stream in one pass
+pub(crate) fn read_header(
+    file: &Path,
+) -> Result<(metadata::BlockList, Option<foreign::ForeignTags>)> {
+    let foreign = foreign::scan(file)?;
+    let blocklist = metadata::BlockList::read(open_scanned_stream(
+        file,
+        &EncodeOptions::default(),
+        foreign.as_ref(),
+    )?)?;
+    Ok((blocklist, foreign))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::path::PathBuf;
 
+    #[test]
+    fn aborted() {
+        let name = PathBuf::from("./samples/16bit.flac");
+        let handler = Arc::new(AtomicBool::new(false));
+        let result = encode_file(&name, handler, &EncodeOptions::default()).unwrap();
+        assert!(result.aborted && !temp_path(&name, "tmp").exists());
+    }
+
+    #[test]
+    fn audit() {
+        let name = PathBuf::from("./samples/audit.flac");
+        let mut data = std::fs::read("./samples/16bit.flac").unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let intact = audit_file(Path::new("./samples/16bit.flac"), handler.clone());
+        let middle = data.len() / 2;
+        data[middle] ^= 0x10;
+        std::fs::write(&name, data).unwrap();
+        let rotten = audit_file(&name, handler);
+        std::fs::remove_file(&name).unwrap();
+        assert!(intact.unwrap() && rotten.is_err());
+    }
+
+    #[test]
+    fn missing_md5() {
+        let names = ["./samples/nomd5.flac", "./samples/nomd5kept.flac"].map(PathBuf::from);
+        for name in &names {
+            std::fs::copy("./samples/16bit.flac", name).unwrap();
+            metadata::update(name, |blocklist| {
+                blocklist.streaminfo_mut().md5 = None;
+                Ok::<(), flac_codec::Error>(())
+            })
+            .unwrap();
+        }
+        let missing = has_md5(&names[0]);
+        let handler = Arc::new(AtomicBool::new(true));
+        let reencoded = encode_file(&names[0], handler.clone(), &EncodeOptions::default());
+        let options = EncodeOptions {
+            min_saving: Some(MinSaving::Percent(50.0)),
+            ..EncodeOptions::default()
+        };
+        let kept = encode_file(&names[1], handler, &options);
+        let md5s = names
+            .iter()
+            .map(|name| metadata::info(name).map(|info| info.md5))
+            .collect::<Vec<_>>();
+        for name in &names {
+            std::fs::remove_file(name).unwrap();
+        }
+
+        let expected = metadata::info("./samples/16bit.flac").unwrap().md5;
+        assert!(!missing.unwrap() && expected.is_some());
+        assert!(reencoded.unwrap().notes == ["computed the missing MD5"]);
+        assert!(kept.unwrap().kept.is_some());
+        assert!(md5s.into_iter().all(|md5| md5.unwrap() == expected));
+    }
+
+    #[test]
+    fn retag() {
+        let name = PathBuf::from("./samples/retag.flac");
+        std::fs::copy("./samples/16bit.flac", &name).unwrap();
+        metadata::update(&name, |blocklist| {
+            blocklist.update::<metadata::VorbisComment>(|comment| comment.insert("ENCODER", "x"));
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let changed = retag_file(&name, &TagPolicy::default(), None).unwrap();
+        let comment = metadata::block::<_, metadata::VorbisComment>(&name).unwrap();
+        let revendored = retag_file(&name, &TagPolicy::default(), Some("test")).unwrap();
+        let vendor = get_vendor(&name).unwrap();
+        std::fs::remove_file(&name).unwrap();
+        assert!(changed && comment.unwrap().get("ENCODER").is_none());
+        assert!(revendored && vendor == "test");
+    }
+
+    #[test]
+    fn false_id3v1() {
+        let name = PathBuf::from("./samples/falseid3.flac");
+        let mut data = std::fs::read("./samples/16bit.flac").unwrap();
+        // audio that happens to look like an ID3v1 tag
+        let end = data.len();
+        data[end - 128..end - 125].copy_from_slice(b"TAG");
+        std::fs::write(&name, &data).unwrap();
+        let result = retag_file(&name, &TagPolicy::default(), Some("test"));
+        let untouched = std::fs::read(&name).unwrap() == data;
+        std::fs::remove_file(&name).unwrap();
+        assert!(result.is_err() && untouched);
+    }
+
+    #[test]
+    fn tidy_padding() {
+        let mut blocklist =
+            metadata::BlockList::read(File::open("./samples/16bit.flac").unwrap()).unwrap();
+        for _ in 0..300 {
+            blocklist.insert(metadata::Padding {
+                size: ((1u32 << 24) - 1).try_into().unwrap(),
+            });
+        }
+        let changed = tidy_blocks(&mut blocklist);
+        let merged = blocklist
+            .get_all::<metadata::Padding>()
+            .map(|padding| u32::from(padding.size))
+            .collect::<Vec<_>>();
+        assert!(changed && merged == [(1 << 24) - 1]);
+    }
+
+    #[test]
+    fn seektable() {
+        let name = PathBuf::from("./samples/seektable.flac");
+        std::fs::copy("./samples/24bit.flac", &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            seektable: SeekTablePolicy {
+                spacing: SeekSpacing::Samples(NonZero::new(4096).unwrap()),
+                add_missing: true,
+            },
+            ..EncodeOptions::default()
+        };
+        encode_file(&name, handler, &options).unwrap();
+        let table = metadata::block::<_, metadata::SeekTable>(&name).unwrap();
+        let regenerated = build_seektable(&name, options.seektable.spacing).unwrap();
+        std::fs::remove_file(&name).unwrap();
+        let table = table.unwrap();
+        assert!(table.points.iter().next().unwrap().sample_offset() == Some(0));
+        assert!(table == regenerated);
+    }
+
+    #[test]
+    fn padding() {
+        let name = PathBuf::from("./samples/padding.flac");
+        std::fs::copy("./samples/32bit.flac", &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            padding: PaddingPolicy::Fixed(8192),
+            ..EncodeOptions::default()
+        };
+        encode_file(&name, handler.clone(), &options).unwrap();
+        let padding = metadata::block::<_, metadata::Padding>(&name).unwrap();
+        let options = EncodeOptions {
+            padding: PaddingPolicy::Fixed(0),
+            ..EncodeOptions::default()
+        };
+        encode_file(&name, handler, &options).unwrap();
+        let empty = metadata::block::<_, metadata::Padding>(&name).unwrap();
+        std::fs::remove_file(&name).unwrap();
+        assert!(u32::from(padding.unwrap().size) == 8192);
+        assert!(empty.is_none());
+    }
+
+    #[test]
+    fn padded_depth() {
+        let name = PathBuf::from("./samples/padded.flac");
+        let mut reader = decode::FlacSampleReader::open("./samples/16bit.flac").unwrap();
+        let streaminfo = reader.metadata().streaminfo().clone();
+        let format = StreamFormat {
+            channels: streaminfo.channel_count() as u32,
+            bits_per_sample: 24,
+            sample_rate: streaminfo.sample_rate(),
+            total_samples: None,
+        };
+        let mut encoder = EncoderBackend::default()
+            .create(&name, &format, &EncoderSettings::default())
+            .unwrap();
+        loop {
+            let buf = reader.fill_buf().unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            let padded = buf.iter().map(|sample| sample << 8).collect::<Vec<_>>();
+            encoder.process(&padded).unwrap();
+            let length = buf.len();
+            reader.consume(length);
+        }
+        encoder.finish().unwrap();
+
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            depth: DepthPolicy::Reduce,
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options).unwrap();
+        let bits = decode::FlacSampleReader::open(&name)
+            .unwrap()
+            .metadata()
+            .streaminfo()
+            .bits_per_sample();
+        std::fs::remove_file(&name).unwrap();
+        assert!(result.true_depth == Some(16) && bits == 16);
+    }
+
+    #[test]
+    fn dual_mono() {
+        let name = PathBuf::from("./samples/dualmono.flac");
+        let mut reader = decode::FlacSampleReader::open("./samples/16bit.flac").unwrap();
+        let streaminfo = reader.metadata().streaminfo().clone();
+        let format = StreamFormat {
+            channels: 2,
+            bits_per_sample: streaminfo.bits_per_sample(),
+            sample_rate: streaminfo.sample_rate(),
+            total_samples: None,
+        };
+        let mut encoder = EncoderBackend::default()
+            .create(&name, &format, &EncoderSettings::default())
+            .unwrap();
+        loop {
+            let buf = reader.fill_buf().unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            let doubled = buf
+                .iter()
+                .step_by(streaminfo.channel_count() as usize)
+                .flat_map(|sample| [*sample, *sample])
+                .collect::<Vec<_>>();
+            encoder.process(&doubled).unwrap();
+            let length = buf.len();
+            reader.consume(length);
+        }
+        encoder.finish().unwrap();
+
+        let handler = Arc::new(AtomicBool::new(true));
+        let detected = encode_file(&name, handler.clone(), &EncodeOptions::default()).unwrap();
+        let options = EncodeOptions {
+            collapse_dual_mono: true,
+            ..EncodeOptions::default()
+        };
+        let collapsed = encode_file(&name, handler, &options).unwrap();
+        let channels = decode::FlacSampleReader::open(&name)
+            .unwrap()
+            .metadata()
+            .streaminfo()
+            .channel_count();
+        std::fs::remove_file(&name).unwrap();
+        assert!(detected.dual_mono == Some(true) && collapsed.dual_mono == Some(true));
+        assert!(channels == 1);
+    }
+
+    #[test]
+    fn convert() {
+        let name = PathBuf::from("./samples/convert.wav");
+        let mut reader = decode::FlacSampleReader::open("./samples/16bit.flac").unwrap();
+        let streaminfo = reader.metadata().streaminfo().clone();
+        let channels = streaminfo.channel_count() as u16;
+        let mut data = Vec::new();
+        loop {
+            let buf = reader.fill_buf().unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            data.extend(buf.iter().flat_map(|sample| (*sample as i16).to_le_bytes()));
+            let length = buf.len();
+            reader.consume(length);
+        }
+
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0".to_vec();
+        wav.extend(channels.to_le_bytes());
+        wav.extend(streaminfo.sample_rate().to_le_bytes());
+        wav.extend((streaminfo.sample_rate() * u32::from(channels) * 2).to_le_bytes());
+        wav.extend((channels * 2).to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav.extend(b"LIST\x10\0\0\0INFOINAM\x04\0\0\0Song");
+        std::fs::write(&name, wav).unwrap();
+
+        let handler = Arc::new(AtomicBool::new(true));
+        let result = convert_file(&name, handler, &EncodeOptions::default());
+        let target = name.with_extension("flac");
+        let converted = metadata::info(&target).map(|info| info.md5);
+        let comment = metadata::block::<_, metadata::VorbisComment>(&target);
+        std::fs::remove_file(&name).unwrap();
+        let _ = std::fs::remove_file(&target);
+        result.unwrap();
+        assert!(converted.unwrap() == metadata::info("./samples/16bit.flac").unwrap().md5);
+        assert!(comment.unwrap().unwrap().get("TITLE") == Some("Song"));
+    }
+
+    #[test]
+    fn ogg_flac() {
+        let name = PathBuf::from("./samples/oggflac.oga");
+        let md5 = metadata::info("./samples/16bit.flac").unwrap().md5;
+        let handler = Arc::new(AtomicBool::new(true));
+
+        ogg::remux(Path::new("./samples/16bit.flac"), &name).unwrap();
+        let kept = encode_file(&name, handler.clone(), &EncodeOptions::default());
+        let blocklist = metadata::BlockList::read(
+            OggFlacReader::new(BufReader::new(File::open(&name).unwrap())).unwrap(),
+        );
+        let vendor = get_vendor(&name);
+
+        let options = EncodeOptions {
+            ogg: OggPolicy::Native,
+            ..EncodeOptions::default()
+        };
+        let native = encode_file(&name, handler, &options);
+        let target = name.with_extension("flac");
+        let converted = metadata::info(&target).map(|info| info.md5);
+        let removed = !name.exists();
+        let _ = std::fs::remove_file(&name);
+        let _ = std::fs::remove_file(&target);
+
+        assert!(kept.unwrap().renamed.is_none());
+        assert!(blocklist.unwrap().streaminfo().md5 == md5);
+        assert!(vendor.unwrap() == EncoderBackend::default().vendor().unwrap());
+        assert!(native.unwrap().renamed == Some(target));
+        assert!(removed && converted.unwrap() == md5);
+    }
+
+    #[test]
+    fn foreign_tags() {
+        let name = PathBuf::from("./samples/foreign.flac");
+        let mut data =
+            b"ID3\x03\x00\x00\x00\x00\x00\x14TIT2\x00\x00\x00\x0A\x00\x00\x00ID3 Title".to_vec();
+        data.extend(std::fs::read("./samples/16bit.flac").unwrap());
+        let mut v1 = [0; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        v1[63..71].copy_from_slice(b"v1 Album");
+        v1[127] = 0xFF;
+        data.extend(v1);
+        std::fs::write(&name, data).unwrap();
+
+        let handler = Arc::new(AtomicBool::new(true));
+        let outcome = encode_file(&name, handler, &EncodeOptions::default());
+        let clean = foreign::scan(&name).map(|tags| tags.is_none());
+        let comment = metadata::block::<_, metadata::VorbisComment>(&name);
+        std::fs::remove_file(&name).unwrap();
+
+        assert!(outcome.unwrap().notes == ["stripped ID3v2, ID3v1 tags, moved TITLE, ALBUM"]);
+        assert!(clean.unwrap());
+        let comment = comment.unwrap().unwrap();
+        assert!(
+            comment.get("TITLE") == Some("ID3 Title") && comment.get("ALBUM") == Some("v1 Album")
+        );
+    }
+
+    #[test]
+    fn vendor() {
+        let name = PathBuf::from("./samples/vendor.flac");
+        std::fs::copy("./samples/16bit.flac", &name).unwrap();
+        metadata::update(&name, |blocklist| {
+            blocklist.update::<metadata::VorbisComment>(|comment| {
+                comment.vendor_string = "reference libFLAC 1.3.2 20170101".to_string()
+            });
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let result = encode_file(&name, handler.clone(), &EncodeOptions::default());
+        let vendor = get_vendor(&name);
+        let options = EncodeOptions {
+            vendor: Some("custom".to_string()),
+            ..EncodeOptions::default()
+        };
+        let overridden = encode_file(&name, handler, &options).map(|_| get_vendor(&name));
+        std::fs::remove_file(&name).unwrap();
+        result.unwrap();
+        assert!(vendor.unwrap() == EncoderBackend::default().vendor().unwrap());
+        // the target only decides what counts as done, files get the real vendor
+        assert!(overridden.unwrap().unwrap() == EncoderBackend::default().vendor().unwrap());
+    }
+
+    #[test]
+    fn min_saving() {
+        let name = PathBuf::from("./samples/minsaving.flac");
+        std::fs::copy("./samples/16bit.flac", &name).unwrap();
+        let original = std::fs::read(&name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            min_saving: Some(MinSaving::Percent(50.0)),
+            ..EncodeOptions::default()
+        };
+        let kept = encode_file(&name, handler.clone(), &options);
+        let untouched = std::fs::read(&name).unwrap() == original;
+
+        let options = EncodeOptions {
+            update_vendor: true,
+            ..options
+        };
+        metadata::update(&name, |blocklist| {
+            blocklist.update::<metadata::VorbisComment>(|comment| {
+                comment.vendor_string = "reference libFLAC 1.3.2 20170101".to_string()
+            });
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let updated = encode_file(&name, handler, &options);
+        let vendor = get_vendor(&name);
+        let md5 = metadata::info(&name).map(|info| info.md5);
+        std::fs::remove_file(&name).unwrap();
+
+        let vendor_target = EncoderBackend::default().vendor().unwrap();
+        assert!(untouched && kept.unwrap().kept == Some(vendor_target.clone()));
+        assert!(updated.unwrap().kept.is_some() && vendor.unwrap() == vendor_target);
+        assert!(md5.unwrap() == metadata::info("./samples/16bit.flac").unwrap().md5);
+    }
+
+    #[test]
+    fn min_saving_with_fixes() {
+        let name = PathBuf::from("./samples/minsavingfix.oga");
+        ogg::remux(Path::new("./samples/16bit.flac"), &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            min_saving: Some(MinSaving::Percent(50.0)),
+            ogg: OggPolicy::Native,
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options);
+        let target = name.with_extension("flac");
+        let converted = target.exists();
+        let _ = std::fs::remove_file(&name);
+        let _ = std::fs::remove_file(&target);
+        let result = result.unwrap();
+        assert!(result.kept.is_none() && result.renamed == Some(target) && converted);
+    }
+
+    #[test]
+    fn min_saving_with_pictures() {
+        let name = PathBuf::from("./samples/minsavingpictures.flac");
+        std::fs::copy("./samples/16bit.flac", &name).unwrap();
+        metadata::update(&name, |blocklist| {
+            blocklist.insert(metadata::Picture {
+                picture_type: metadata::PictureType::FrontCover,
+                media_type: "image/png".to_string(),
+                description: String::new(),
+                width: 1,
+                height: 1,
+                color_depth: 24,
+                colors_used: None,
+                data: vec![0; 16],
+            });
+            Ok::<(), flac_codec::Error>(())
+        })
+        .unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            min_saving: Some(MinSaving::Percent(50.0)),
+            pictures: PicturePolicy {
+                strip: true,
+                ..PicturePolicy::default()
+            },
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options);
+        let pictures = metadata::BlockList::read(File::open(&name).unwrap())
+            .map(|blocklist| blocklist.get_all::<metadata::Picture>().count());
+        std::fs::remove_file(&name).unwrap();
+        assert!(result.unwrap().kept.is_none() && pictures.unwrap() == 0);
+    }
+
+    #[test]
+    #[cfg(feature = "libflac")]
+    fn trials() {
+        let name = PathBuf::from("./samples/trials.flac");
+        std::fs::copy("./samples/16bit.flac", &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let best = EncoderSettings {
+            exhaustive: true,
+            ..EncoderSettings::default()
+        };
+        let options = EncodeOptions {
+            trials: vec![
+                EncoderSettings {
+                    level: 0,
+                    ..EncoderSettings::default()
+                },
+                best.clone(),
+            ],
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options);
+        let md5 = metadata::info(&name).map(|info| info.md5);
+        let leftovers = ["trials.flac.tmp", "trials.flac.0.tmp", "trials.flac.1.tmp"]
+            .iter()
+            .any(|leftover| Path::new("./samples").join(leftover).exists());
+        std::fs::remove_file(&name).unwrap();
+        assert!(result.unwrap().settings == Some(best) && !leftovers);
+        assert!(md5.unwrap() == metadata::info("./samples/16bit.flac").unwrap().md5);
+    }
+
+    #[test]
+    fn rust_encoder() {
+        let name = PathBuf::from("./samples/rust.flac");
+        std::fs::copy("./samples/24bit.flac", &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            encoder: EncoderBackend::Rust,
+            trials: vec![EncoderSettings::default()],
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options);
+        let md5 = metadata::info(&name).map(|info| info.md5);
+        let vendor = get_vendor(&name);
+        std::fs::remove_file(&name).unwrap();
+        assert!(result.unwrap().settings == Some(EncoderSettings::default()));
+        assert!(md5.unwrap() == metadata::info("./samples/24bit.flac").unwrap().md5);
+        assert!(vendor.unwrap() == metadata::VorbisComment::default().vendor_string);
+    }
+
+    #[test]
+    #[ignore = "needs the flac binary on the PATH"]
+    fn external_encoder() {
+        let name = PathBuf::from("./samples/external.flac");
+        std::fs::copy("./samples/24bit.flac", &name).unwrap();
+        let handler = Arc::new(AtomicBool::new(true));
+        let options = EncodeOptions {
+            encoder: EncoderBackend::External(PathBuf::from("flac")),
+            ..EncodeOptions::default()
+        };
+        let result = encode_file(&name, handler, &options);
+        let md5 = metadata::info(&name).map(|info| info.md5);
+        std::fs::remove_file(&name).unwrap();
+        result.unwrap();
+        assert!(md5.unwrap() == metadata::info("./samples/24bit.flac").unwrap().md5);
+    }
+
     #[test]
     fn bit16() {
         let name = PathBuf::from("./samples/16bit.flac");
         let tempname = PathBuf::from("./samples/16bit.flac.temp");
         std::fs::copy(&name, &tempname).unwrap();
         let handler = Arc::new(AtomicBool::new(true));
-        encode_file(&name, handler).unwrap();
+        encode_file(&name, handler, &EncodeOptions::default()).unwrap();
         let output = std::process::Command::new("flac")
             .arg("-wts")
             .arg(&name)
@@ -186,7 +2064,7 @@ mod tests {
         let tempname = PathBuf::from("./samples/24bit.flac.temp");
         std::fs::copy(&name, &tempname).unwrap();
         let handler = Arc::new(AtomicBool::new(true));
-        encode_file(&name, handler).unwrap();
+        encode_file(&name, handler, &EncodeOptions::default()).unwrap();
         let output = std::process::Command::new("flac")
             .arg("-wts")
             .arg(&name)
@@ -201,7 +2079,7 @@ mod tests {
         let tempname = PathBuf::from("./samples/32bit.flac.temp");
         std::fs::copy(&name, &tempname).unwrap();
         let handler = Arc::new(AtomicBool::new(true));
-        encode_file(&name, handler).unwrap();
+        encode_file(&name, handler, &EncodeOptions::default()).unwrap();
         let output = std::process::Command::new("flac")
             .arg("-wts")
             .arg(&name)
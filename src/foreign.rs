@@ -0,0 +1,263 @@
+use crate::{id3, ogg, tags};
+use anyhow::{Result, anyhow};
+use flac_codec::metadata::VorbisComment;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// APEv2 keys whose Vorbis comment field names differ, other keys are used uppercased
+const APE_KEYS: [(&str, &str); 5] = [
+    ("YEAR", "DATE"),
+    ("ALBUM ARTIST", "ALBUMARTIST"),
+    ("PUBLISHER", "LABEL"),
+    ("DEBUT ALBUM", "ORIGINALALBUM"),
+    ("RECORD DATE", "DATE"),
+];
+
+/// Tags written around a FLAC stream by tools that don't speak Vorbis comments
+pub(crate) struct ForeignTags {
+    /// Offset of the `fLaC` signature, after any ID3v2 tags
+    pub(crate) start: u64,
+    /// Offset where trailing APEv2 and ID3v1 tags begin
+    pub(crate) end: u64,
+    /// Kind of each tag found, along with its fields mapped to Vorbis comment fields
+    tags: Vec<(&'static str, Vec<(String, String)>)>,
+}
+
+impl ForeignTags {
+    /// Adds the fields of these tags to `comment`, returning a note on what was moved.
+    ///
+    /// Fields already in the comment win, and among the foreign tags ID3v2 wins over APEv2,
+    /// which wins over ID3v1.
+    pub(crate) fn migrate(&self, comment: &mut VorbisComment) -> String {
+        let existing = comment
+            .fields
+            .iter()
+            .filter_map(|field| field.split_once('='))
+            .map(|(name, _)| name.to_ascii_uppercase())
+            .collect::<HashSet<_>>();
+        let ranked = ["ID3v2", "APEv2", "ID3v1"]
+            .iter()
+            .flat_map(|kind| self.tags.iter().filter(move |(tag, _)| tag == kind))
+            .collect::<Vec<_>>();
+
+        let mut moved = Vec::<String>::new();
+        let mut taken = HashSet::new();
+        for (_, fields) in &ranked {
+            let names = fields
+                .iter()
+                .map(|(name, _)| name.clone())
+                .filter(|name| !existing.contains(name) && !taken.contains(name))
+                .collect::<HashSet<_>>();
+            for (name, value) in fields.iter().filter(|(name, _)| names.contains(name)) {
+                comment.fields.push(format!("{name}={value}"));
+                if !moved.contains(name) {
+                    moved.push(name.clone());
+                }
+            }
+            taken.extend(names);
+        }
+
+        let mut kinds = ranked.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+        kinds.dedup();
+        if moved.is_empty() {
+            format!("stripped {} tags", kinds.join(", "))
+        } else {
+            format!(
+                "stripped {} tags, moved {}",
+                kinds.join(", "),
+                moved.join(", ")
+            )
+        }
+    }
+}
+
+/// Splits an APEv2 item key into Vorbis comment field names and values
+fn ape_fields(key: &str, value: &str) -> Vec<(String, String)> {
+    let key = key.to_ascii_uppercase();
+    if !tags::is_field_name(&key) {
+        return Vec::new();
+    }
+    value
+        .split('\0')
+        .filter(|value| !value.is_empty())
+        .flat_map(|value| match key.as_str() {
+            "TRACK" => id3::numbering(value, "TRACKNUMBER", "TRACKTOTAL"),
+            "DISC" => id3::numbering(value, "DISCNUMBER", "DISCTOTAL"),
+            _ => {
+                let name = APE_KEYS
+                    .iter()
+                    .find(|(ape, _)| *ape == key)
+                    .map_or(key.as_str(), |(_, name)| name);
+                vec![(name.to_string(), value.to_string())]
+            }
+        })
+        .collect()
+}
+
+/// Maps the text items of an APEv2 tag, given without its header and footer
+fn parse_ape(items: &[u8], count: u32) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    for _ in 0..count {
+        let Some(header) = items.get(position..position + 8) else {
+            break;
+        };
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let flags = u32::from_le_bytes(header[4..].try_into().unwrap());
+        position += 8;
+        let Some(key_length) = items[position..].iter().position(|byte| *byte == 0) else {
+            break;
+        };
+        let key = String::from_utf8_lossy(&items[position..position + key_length]).into_owned();
+        position += key_length + 1;
+        let Some(value) = items.get(position..position + length) else {
+            break;
+        };
+        position += length;
+        // binary and external items, cover art among them, aren't text
+        if flags & 0x06 == 0 {
+            fields.extend(ape_fields(&key, &String::from_utf8_lossy(value)));
+        }
+    }
+    fields
+}
+
+/// Looks for ID3v2 tags before a native FLAC stream and APEv2 or ID3v1 tags after it.
+///
+/// Returns `None` for clean files and for Ogg files, whose pages leave no room for them.
+pub(crate) fn scan(path: &Path) -> Result<Option<ForeignTags>> {
+    if ogg::is_ogg(path) {
+        return Ok(None);
+    }
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut tags = Vec::new();
+
+    // some taggers stack a new ID3v2 tag on top of the old one
+    let mut start = 0;
+    loop {
+        let mut header = [0; 10];
+        file.seek(SeekFrom::Start(start))?;
+        if file.read_exact(&mut header).is_err() {
+            break;
+        }
+        let Some(size) = id3::tag_size(&header) else {
+            break;
+        };
+        let mut tag = header.to_vec();
+        tag.resize(size, 0);
+        file.read_exact(&mut tag[10..])?;
+        tags.push(("ID3v2", id3::parse(&tag)));
+        start += size as u64;
+    }
+    if start > 0 {
+        let mut signature = [0; 4];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut signature)?;
+        if &signature != b"fLaC" {
+            return Err(anyhow!("no FLAC stream after the ID3v2 tag"));
+        }
+    }
+
+    let mut end = length;
+    if end >= start + 128 {
+        let mut tag = [0; 128];
+        file.seek(SeekFrom::Start(end - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag[..3] == b"TAG" {
+            tags.push(("ID3v1", id3::parse_v1(&tag)));
+            end -= 128;
+        }
+    }
+    if end >= start + 32 {
+        let mut footer = [0; 32];
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] == b"APETAGEX" {
+            // the size covers the items and footer, the optional header comes on top
+            let size = u64::from(u32::from_le_bytes(footer[12..16].try_into().unwrap()));
+            let count = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+            if size < 32 || size + header > end - start {
+                return Err(anyhow!("corrupt APEv2 tag"));
+            }
+            let mut items = vec![0; size as usize - 32];
+            file.seek(SeekFrom::Start(end - size))?;
+            file.read_exact(&mut items)?;
+            tags.push(("APEv2", parse_ape(&items, count)));
+            end -= size + header;
+        }
+    }
+
+    Ok((!tags.is_empty()).then_some(ForeignTags { start, end, tags }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_stream() {
+        let name = Path::new("./samples/wrapped.flac");
+        let mut data =
+            b"ID3\x03\x00\x00\x00\x00\x00\x14TIT2\x00\x00\x00\x0A\x00\x00\x00ID3 Title".to_vec();
+        let start = data.len() as u64;
+        data.extend(std::fs::read("./samples/16bit.flac").unwrap());
+        let end = data.len() as u64;
+
+        let mut items = Vec::new();
+        for (key, value) in [
+            ("Artist", "APE Artist"),
+            ("Track", "4/9"),
+            ("Title", "APE Title"),
+        ] {
+            items.extend((value.len() as u32).to_le_bytes());
+            items.extend(0u32.to_le_bytes());
+            items.extend(key.as_bytes());
+            items.push(0);
+            items.extend(value.as_bytes());
+        }
+        data.extend(&items);
+        data.extend(b"APETAGEX");
+        data.extend(2000u32.to_le_bytes());
+        data.extend((items.len() as u32 + 32).to_le_bytes());
+        data.extend(3u32.to_le_bytes());
+        data.extend([0; 12]);
+
+        let mut v1 = [0; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        v1[3..11].copy_from_slice(b"v1 Title");
+        v1[93..97].copy_from_slice(b"1999");
+        v1[127] = 17;
+        data.extend(v1);
+        std::fs::write(name, data).unwrap();
+
+        let tags = scan(name);
+        std::fs::remove_file(name).unwrap();
+        let tags = tags.unwrap().unwrap();
+        assert!(tags.start == start && tags.end == end);
+
+        let mut comment = VorbisComment {
+            vendor_string: String::new(),
+            fields: vec!["ARTIST=FLAC Artist".to_string()],
+        };
+        let note = tags.migrate(&mut comment);
+        let expected = [
+            "ARTIST=FLAC Artist",
+            "TITLE=ID3 Title",
+            "TRACKNUMBER=4",
+            "TRACKTOTAL=9",
+            "DATE=1999",
+            "GENRE=Rock",
+        ];
+        assert!(comment.fields == expected);
+        assert!(
+            note == "stripped ID3v2, APEv2, ID3v1 tags, moved TITLE, TRACKNUMBER, TRACKTOTAL, DATE, GENRE"
+        );
+    }
+}
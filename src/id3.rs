@@ -0,0 +1,376 @@
+use crate::tags;
+
+/// Vorbis comment fields for ID3v2 text frames
+const TEXT_FRAMES: [(&str, &str); 13] = [
+    ("TIT2", "TITLE"),
+    ("TIT3", "SUBTITLE"),
+    ("TPE1", "ARTIST"),
+    ("TPE2", "ALBUMARTIST"),
+    ("TALB", "ALBUM"),
+    ("TCON", "GENRE"),
+    ("TYER", "DATE"),
+    ("TDRC", "DATE"),
+    ("TCOM", "COMPOSER"),
+    ("TCOP", "COPYRIGHT"),
+    ("TPUB", "LABEL"),
+    ("TSRC", "ISRC"),
+    ("TBPM", "BPM"),
+];
+
+/// ID3v1 genres, by index
+const GENRES: [&str; 80] = [
+    "Blues",
+    "Classic Rock",
+    "Country",
+    "Dance",
+    "Disco",
+    "Funk",
+    "Grunge",
+    "Hip-Hop",
+    "Jazz",
+    "Metal",
+    "New Age",
+    "Oldies",
+    "Other",
+    "Pop",
+    "R&B",
+    "Rap",
+    "Reggae",
+    "Rock",
+    "Techno",
+    "Industrial",
+    "Alternative",
+    "Ska",
+    "Death Metal",
+    "Pranks",
+    "Soundtrack",
+    "Euro-Techno",
+    "Ambient",
+    "Trip-Hop",
+    "Vocal",
+    "Jazz+Funk",
+    "Fusion",
+    "Trance",
+    "Classical",
+    "Instrumental",
+    "Acid",
+    "House",
+    "Game",
+    "Sound Clip",
+    "Gospel",
+    "Noise",
+    "AlternRock",
+    "Bass",
+    "Soul",
+    "Punk",
+    "Space",
+    "Meditative",
+    "Instrumental Pop",
+    "Instrumental Rock",
+    "Ethnic",
+    "Gothic",
+    "Darkwave",
+    "Techno-Industrial",
+    "Electronic",
+    "Pop-Folk",
+    "Eurodance",
+    "Dream",
+    "Southern Rock",
+    "Comedy",
+    "Cult",
+    "Gangsta",
+    "Top 40",
+    "Christian Rap",
+    "Pop/Funk",
+    "Jungle",
+    "Native American",
+    "Cabaret",
+    "New Wave",
+    "Psychadelic",
+    "Rave",
+    "Showtunes",
+    "Trailer",
+    "Lo-Fi",
+    "Tribal",
+    "Acid Punk",
+    "Acid Jazz",
+    "Polka",
+    "Retro",
+    "Musical",
+    "Rock & Roll",
+    "Hard Rock",
+];
+
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7F))
+}
+
+/// Undoes unsynchronisation, which inserts a zero byte after every 0xFF
+fn resync(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    for (index, byte) in data.iter().enumerate() {
+        if *byte == 0 && index > 0 && data[index - 1] == 0xFF {
+            continue;
+        }
+        output.push(*byte);
+    }
+    output
+}
+
+/// Decodes a text frame body into its null separated strings
+fn decode_text(encoding: u8, data: &[u8]) -> Vec<String> {
+    let text = match encoding {
+        0 => data.iter().map(|byte| char::from(*byte)).collect(),
+        1 | 2 => {
+            let (little_endian, data) = match data {
+                [0xFF, 0xFE, rest @ ..] => (true, rest),
+                [0xFE, 0xFF, rest @ ..] => (false, rest),
+                _ => (false, data),
+            };
+            let units = data
+                .chunks_exact(2)
+                .map(|pair| {
+                    if little_endian {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+    // a BOM may start every string after the first
+    let mut strings = text
+        .split('\0')
+        .map(|value| value.trim_start_matches('\u{FEFF}').to_string())
+        .collect::<Vec<_>>();
+    while strings.last().is_some_and(|value| value.is_empty()) {
+        strings.pop();
+    }
+    strings
+}
+
+/// Splits "3/12" style numbering into the number and total fields
+pub(crate) fn numbering(value: &str, number: &str, total: &str) -> Vec<(String, String)> {
+    match value.split_once('/') {
+        Some((index, count)) if !count.trim().is_empty() => vec![
+            (number.to_string(), index.trim().to_string()),
+            (total.to_string(), count.trim().to_string()),
+        ],
+        _ => vec![(number.to_string(), value.trim().to_string())],
+    }
+}
+
+/// Total size of the ID3v2 tag starting at `header`, footer included.
+///
+/// Returns `None` if `header` doesn't start with an ID3v2 header.
+pub(crate) fn tag_size(header: &[u8]) -> Option<usize> {
+    if header.len() < 10 || &header[..3] != b"ID3" || header[3] == 0xFF || header[4] == 0xFF {
+        return None;
+    }
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + synchsafe(&header[6..10]) + footer)
+}
+
+/// Maps the fixed fields of a 128 byte ID3v1 or ID3v1.1 tag to Vorbis comment fields.
+///
+/// Genres are given by index into a table, only the original 80 are mapped.
+pub(crate) fn parse_v1(tag: &[u8]) -> Vec<(String, String)> {
+    if tag.len() != 128 || &tag[..3] != b"TAG" {
+        return Vec::new();
+    }
+    let text = |range: std::ops::Range<usize>| {
+        let field = &tag[range];
+        let end = field
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(field.len());
+        field[..end]
+            .iter()
+            .map(|byte| char::from(*byte))
+            .collect::<String>()
+            .trim()
+            .to_string()
+    };
+    // ID3v1.1 takes the last two comment bytes for a zero and the track number
+    let track = (tag[125] == 0 && tag[126] != 0).then(|| tag[126]);
+
+    let mut fields = [
+        ("TITLE", text(3..33)),
+        ("ARTIST", text(33..63)),
+        ("ALBUM", text(63..93)),
+        ("DATE", text(93..97)),
+        ("COMMENT", text(97..if track.is_some() { 125 } else { 127 })),
+    ]
+    .into_iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+    if let Some(track) = track {
+        fields.push(("TRACKNUMBER".to_string(), track.to_string()));
+    }
+    if let Some(genre) = GENRES.get(usize::from(tag[127])) {
+        fields.push(("GENRE".to_string(), genre.to_string()));
+    }
+    fields
+}
+
+/// Maps the frames of an ID3v2.3 or ID3v2.4 tag to Vorbis comment fields.
+///
+/// Frames without a Vorbis counterpart, pictures among them, are left out.
+pub(crate) fn parse(tag: &[u8]) -> Vec<(String, String)> {
+    let Some(size) = tag_size(tag) else {
+        return Vec::new();
+    };
+    let version = tag[3];
+    let flags = tag[5];
+    // ID3v2.2 uses three letter frame ids that aren't worth supporting
+    if !(3..=4).contains(&version) {
+        return Vec::new();
+    }
+
+    let body = &tag[10..size.min(tag.len())];
+    let body = if version == 3 && flags & 0x80 != 0 {
+        resync(body)
+    } else {
+        body.to_vec()
+    };
+    let mut position = 0;
+    if flags & 0x40 != 0 && body.len() >= 4 {
+        position = if version == 3 {
+            4 + u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize
+        } else {
+            synchsafe(&body[..4])
+        };
+    }
+
+    let mut fields = Vec::new();
+    while position + 10 <= body.len() {
+        let header = &body[position..position + 10];
+        // the rest is padding
+        if header[0] == 0 {
+            break;
+        }
+        let id = String::from_utf8_lossy(&header[..4]).into_owned();
+        let length = if version == 4 {
+            synchsafe(&header[4..8])
+        } else {
+            u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize
+        };
+        let format = header[9];
+        position += 10;
+        let Some(data) = body.get(position..position + length) else {
+            break;
+        };
+        position += length;
+
+        let (compressed, encrypted) = if version == 4 {
+            (format & 0x08 != 0, format & 0x04 != 0)
+        } else {
+            (format & 0x80 != 0, format & 0x40 != 0)
+        };
+        if compressed || encrypted || data.is_empty() {
+            continue;
+        }
+        let mut data = data.to_vec();
+        if version == 4 {
+            if format & 0x01 != 0 && data.len() >= 4 {
+                data.drain(..4);
+            }
+            if format & 0x02 != 0 {
+                data = resync(&data);
+            }
+        }
+        if data.is_empty() {
+            continue;
+        }
+        let (encoding, text) = (data[0], &data[1..]);
+
+        match id.as_str() {
+            "TRCK" => {
+                if let Some(value) = decode_text(encoding, text).first() {
+                    fields.extend(numbering(value, "TRACKNUMBER", "TRACKTOTAL"));
+                }
+            }
+            "TPOS" => {
+                if let Some(value) = decode_text(encoding, text).first() {
+                    fields.extend(numbering(value, "DISCNUMBER", "DISCTOTAL"));
+                }
+            }
+            "TXXX" => {
+                if let [description, value, ..] = decode_text(encoding, text).as_slice()
+                    && tags::is_field_name(description)
+                {
+                    fields.push((description.to_ascii_uppercase(), value.clone()));
+                }
+            }
+            // the language code comes before the description
+            "COMM" if text.len() > 3 => {
+                if let [_, value, ..] = decode_text(encoding, &text[3..]).as_slice() {
+                    fields.push(("COMMENT".to_string(), value.clone()));
+                }
+            }
+            _ => {
+                if let Some((_, name)) = TEXT_FRAMES.iter().find(|(frame, _)| *frame == id) {
+                    for value in decode_text(encoding, text) {
+                        fields.push((name.to_string(), value));
+                    }
+                }
+            }
+        }
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: &str, body: &[u8]) -> Vec<u8> {
+        let mut frame = id.as_bytes().to_vec();
+        frame.extend((body.len() as u32).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(body);
+        frame
+    }
+
+    #[test]
+    fn v23_frames() {
+        let mut frames = frame("TIT2", b"\x00Title");
+        frames.extend(frame("TRCK", b"\x033/12"));
+        frames.extend(frame("TPE1", b"\x01\xFF\xFEA\x00b\x00"));
+        frames.extend(frame("COMM", b"\x00engdesc\x00Nice"));
+        frames.extend(frame("TXXX", b"\x00Mood\x00Calm"));
+        frames.extend(frame("TXXX", b"\x00a=b\x00Lost"));
+        frames.extend(frame("APIC", b"\x00image/png\x00\x03\x00data"));
+        frames.extend([0; 16]);
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len();
+        tag.extend([
+            (size >> 21) as u8 & 0x7F,
+            (size >> 14) as u8 & 0x7F,
+            (size >> 7) as u8 & 0x7F,
+            size as u8 & 0x7F,
+        ]);
+        tag.extend(frames);
+
+        assert!(tag_size(&tag) == Some(tag.len()));
+        let fields = parse(&tag);
+        let expected = [
+            ("TITLE", "Title"),
+            ("TRACKNUMBER", "3"),
+            ("TRACKTOTAL", "12"),
+            ("ARTIST", "Ab"),
+            ("COMMENT", "Nice"),
+            ("MOOD", "Calm"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert!(fields == expected);
+    }
+}
@@ -1,16 +1,28 @@
 mod db;
 mod files;
 mod flac;
+mod foreign;
+mod id3;
+mod ogg;
+mod pcm;
+mod pictures;
+mod spectrum;
+mod tags;
+mod throttle;
 use anyhow::Result;
-use clap::{Arg, ArgAction, Command, ValueHint, command, value_parser};
+use clap::{
+    Arg, ArgAction, ArgMatches, Command, ValueHint, command, error::ErrorKind, value_parser,
+};
 use clap_complete::{Generator, Shell, generate};
 use console::style;
 use std::{
+    num::NonZero,
     path::PathBuf,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 fn build_cli() -> Command {
@@ -35,6 +47,12 @@ fn build_cli() -> Command {
                 .help("Clean and dedupe database")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("shared")
+                .long("shared")
+                .help("Cooperate with other instances using the same database")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("threads")
                 .short('t')
@@ -45,6 +63,352 @@ fn build_cli() -> Command {
                 .value_parser(value_parser!(usize))
                 .default_value("4"),
         )
+        .arg(
+            Arg::new("order")
+                .short('o')
+                .long("order")
+                .help("Order in which files are reencoded")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(db::Order))
+                .default_value("any"),
+        )
+        .arg(
+            Arg::new("limit")
+                .short('l')
+                .long("limit")
+                .help("Stop after N files, or N bytes when given a size like 20G")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_limit),
+        )
+        .arg(
+            Arg::new("until")
+                .long("until")
+                .help("Stop starting new files at this local time (HH:MM)")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_clock),
+        )
+        .arg(
+            Arg::new("max_duration")
+                .long("max-duration")
+                .help("Stop starting new files after this long, like 90m or 2h30m")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_duration),
+        )
+        .arg(
+            Arg::new("max_bytes")
+                .long("max-bytes")
+                .help("Stop starting new files after taking on this much data, like 50G")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("read_limit")
+                .long("read-limit")
+                .help("Cap reading to this many bytes per second, like 20M")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("write_limit")
+                .long("write-limit")
+                .help("Cap writing to this many bytes per second, like 20M")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("nice")
+                .long("nice")
+                .help("Lower CPU priority to this niceness")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(value_parser!(i32).range(0..=19)),
+        )
+        .arg(
+            Arg::new("idle_io")
+                .long("idle-io")
+                .help("Only use disk time no one else wants (Linux)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_load")
+                .long("max-load")
+                .help("Drop to a single thread while the load from other processes is above this")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("hardlinks")
+                .long("hardlinks")
+                .help("Reencode files hardlinked under several paths once and relink the others, or skip them")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(files::HardlinkPolicy))
+                .default_value("relink"),
+        )
+        .arg(
+            Arg::new("retag")
+                .long("retag")
+                .help("Apply the tag policy to indexed files without reencoding")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("retag_vendor")
+                .long("retag-vendor")
+                .help("While retagging, also set the target vendor string instead of reencoding")
+                .action(ArgAction::SetTrue)
+                .requires("retag"),
+        )
+        .arg(
+            Arg::new("strip_tag")
+                .long("strip-tag")
+                .help("Also remove this tag, besides encoder tags")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("keep_tag")
+                .long("keep-tag")
+                .help("Never remove this tag")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("rename_tag")
+                .long("rename-tag")
+                .help("Rename a tag, like \"ALBUM ARTIST=ALBUMARTIST\"")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_rename),
+        )
+        .arg(
+            Arg::new("uppercase_tags")
+                .long("uppercase-tags")
+                .help("Uppercase tag names")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("trim_tags")
+                .long("trim-tags")
+                .help("Trim whitespace around tag values")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedupe_tags")
+                .long("dedupe-tags")
+                .help("Remove repeated tag values")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("drop_empty_tags")
+                .long("drop-empty-tags")
+                .help("Remove tags with an empty value")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("seektable_spacing")
+                .long("seektable-spacing")
+                .help("Seek point spacing in seconds like 10s, or in samples like 441000")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_spacing)
+                .default_value("10s"),
+        )
+        .arg(
+            Arg::new("add_seektable")
+                .long("add-seektable")
+                .help("Add a seektable to files that lack one")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("padding")
+                .long("padding")
+                .help("Padding after the metadata: a size like 8K, a percentage like 0.5%, or keep")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_padding),
+        )
+        .arg(
+            Arg::new("bit_depth")
+                .long("bit-depth")
+                .help("Detect low bits that are zero in every sample, and optionally reencode without them")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(flac::DepthPolicy))
+                .default_value("keep"),
+        )
+        .arg(
+            Arg::new("collapse_dual_mono")
+                .long("collapse-dual-mono")
+                .help("Reencode stereo files with identical channels as mono")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ogg")
+                .long("ogg")
+                .help("Container to reencode Ogg FLAC files into")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(flac::OggPolicy))
+                .default_value("keep"),
+        )
+        .arg(
+            Arg::new("encoder")
+                .long("encoder")
+                .help("Encoder for new files, libflac unless built without it")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(flac::EncoderBackend)),
+        )
+        .arg(
+            Arg::new("flac_binary")
+                .long("flac-binary")
+                .help("Encode new files by piping samples through this flac binary")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::ExecutablePath)
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with("encoder"),
+        )
+        .arg(
+            Arg::new("target_vendor")
+                .long("target-vendor")
+                .help("Vendor string files must have to count as reencoded [default: the encoder's]")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("trial")
+                .long("trial")
+                .help("Encode with each of these settings in parallel and keep the smallest result, like 8, 8e or 8:tukey(5e-1);partial_tukey(2)")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_trial),
+        )
+        .arg(
+            Arg::new("min_saving")
+                .long("min-saving")
+                .help("Keep files that only need a new vendor unless reencoding saves this much: a size like 4K, or a percentage like 1%")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_min_saving),
+        )
+        .arg(
+            Arg::new("update_vendor")
+                .long("update-vendor")
+                .help("Rewrite the vendor string of originals kept for saving too little")
+                .action(ArgAction::SetTrue)
+                .requires("min_saving"),
+        )
+        .arg(
+            Arg::new("convert")
+                .long("convert")
+                .help("Also index WAV, AIFF and W64 files and convert them to FLAC")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("remove_sources")
+                .long("remove-sources")
+                .help("Delete converted sources once the FLAC file is verified")
+                .action(ArgAction::SetTrue)
+                .requires("convert"),
+        )
+        .arg(
+            Arg::new("detect_lossy")
+                .long("detect-lossy")
+                .help("Analyse indexed files for signs of lossy origin and report likely ones")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("lossy_threshold")
+                .long("lossy-threshold")
+                .help("Confidence from 0 to 1 at which files are reported as likely lossy")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_confidence)
+                .default_value("0.5"),
+        )
+        .arg(
+            Arg::new("audit")
+                .long("audit")
+                .help("Verify frame CRCs and MD5s of indexed files, least recently verified first, and report failures")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("audit_age")
+                .long("audit-age")
+                .help("Skip files verified more recently than this, like 30d")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_duration)
+                .requires("audit"),
+        )
+        .arg(
+            Arg::new("missing_md5")
+                .long("missing-md5")
+                .help("List files indexed without an MD5 signature, and whether reencoding has computed one")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dupes")
+                .long("dupes")
+                .help("List groups of indexed files with bit-identical audio, whatever their tags")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dupes_script")
+                .long("dupes-script")
+                .help("Print a script hardlinking or deleting all but the first file of each group instead, losing their tags")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(files::DupeScript))
+                .requires("dupes"),
+        )
+        .arg(
+            Arg::new("pictures")
+                .long("pictures")
+                .help("Apply the picture policy to indexed files without reencoding")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip_pictures")
+                .long("strip-pictures")
+                .help("Remove all embedded pictures")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("front_cover_only")
+                .long("front-cover-only")
+                .help("Remove all embedded pictures but the front cover")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("extract_cover")
+                .long("extract-cover")
+                .help("Save the embedded front cover next to the file if there is none")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedupe_pictures")
+                .long("dedupe-pictures")
+                .help("Remove identical embedded pictures")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_picture_size")
+                .long("max-picture-size")
+                .help("Report embedded pictures larger than this, like 1M")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Other)
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("drop_oversized")
+                .long("drop-oversized")
+                .help("Remove pictures larger than --max-picture-size instead of reporting them")
+                .action(ArgAction::SetTrue)
+                .requires("max_picture_size"),
+        )
         .arg(
             Arg::new("db")
                 .short('d')
@@ -64,6 +428,288 @@ fn build_cli() -> Command {
         )
 }
 
+/// Parses a confidence between 0 and 1
+fn parse_confidence(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(confidence) if (0.0..=1.0).contains(&confidence) => Ok(confidence),
+        _ => Err(format!("{value} is not a number between 0 and 1")),
+    }
+}
+
+/// Parses sizes like `4096`, `512K`, `20G` or `1TiB` into bytes
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {value}"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {unit}")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
+
+fn parse_limit(value: &str) -> Result<files::Limit, String> {
+    if value.trim().chars().all(|c| c.is_ascii_digit()) {
+        value
+            .trim()
+            .parse()
+            .map(files::Limit::Files)
+            .map_err(|_| format!("invalid limit: {value}"))
+    } else {
+        parse_size(value).map(files::Limit::Bytes)
+    }
+}
+
+/// Parses durations like `3600`, `90m` or `1h30m` into seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration: {value}");
+    if value.trim().is_empty() {
+        return Err(invalid());
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("invalid duration unit: {c}")),
+        };
+        let amount: u64 = number.parse().map_err(|_| invalid())?;
+        total = amount
+            .checked_mul(multiplier)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let amount: u64 = number.parse().map_err(|_| invalid())?;
+        total = total.checked_add(amount).ok_or_else(invalid)?;
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Parses a wall clock time like `07:30` into seconds since midnight
+fn parse_clock(value: &str) -> Result<u64, String> {
+    let (hours, minutes) = value
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("invalid time: {value}"))?;
+    match (hours.parse::<u64>(), minutes.parse::<u64>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 3600 + minutes * 60),
+        _ => Err(format!("invalid time: {value}")),
+    }
+}
+
+/// Returns the local time of day in seconds since midnight
+#[cfg(unix)]
+fn local_clock() -> Result<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return Err(anyhow::anyhow!("Failed to read local time"));
+    }
+    Ok(tm.tm_hour as u64 * 3600 + tm.tm_min as u64 * 60 + tm.tm_sec as u64)
+}
+
+/// Returns the time of day in seconds since midnight, in UTC
+#[cfg(not(unix))]
+fn local_clock() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() % (24 * 3600))
+}
+
+/// Earliest point at which any of the time limits is hit
+fn run_deadline(until: Option<u64>, max_duration: Option<Duration>) -> Result<Option<Instant>> {
+    let now = Instant::now();
+    let until = match until {
+        Some(clock) => {
+            let current = local_clock()?;
+            let wait = (clock + 24 * 3600 - current) % (24 * 3600);
+            Some(now + Duration::from_secs(wait))
+        }
+        None => None,
+    };
+    // a limit too far away for the clock to represent is no limit at all
+    let duration = max_duration.and_then(|duration| now.checked_add(duration));
+    Ok(match (until, duration) {
+        (Some(until), Some(duration)) => Some(until.min(duration)),
+        (until, duration) => until.or(duration),
+    })
+}
+
+fn parse_spacing(value: &str) -> Result<flac::SeekSpacing, String> {
+    let value = value.trim();
+    if let Some(seconds) = value.strip_suffix('s') {
+        seconds
+            .parse::<NonZero<u8>>()
+            .map(flac::SeekSpacing::Seconds)
+            .map_err(|_| format!("invalid spacing, expected 1s to 255s: {value}"))
+    } else {
+        value
+            .parse::<NonZero<u64>>()
+            .map(flac::SeekSpacing::Samples)
+            .map_err(|_| format!("invalid spacing: {value}"))
+    }
+}
+
+fn parse_padding(value: &str) -> Result<flac::PaddingPolicy, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("keep") {
+        Ok(flac::PaddingPolicy::Keep)
+    } else if let Some(percent) = value.strip_suffix('%') {
+        match percent.parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => {
+                Ok(flac::PaddingPolicy::Percent(percent))
+            }
+            _ => Err(format!("invalid percentage: {value}")),
+        }
+    } else {
+        parse_size(value)?
+            .try_into()
+            .map(flac::PaddingPolicy::Fixed)
+            .map_err(|_| format!("padding too large: {value}"))
+    }
+}
+
+fn parse_min_saving(value: &str) -> Result<flac::MinSaving, String> {
+    let value = value.trim();
+    if let Some(percent) = value.strip_suffix('%') {
+        match percent.parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => {
+                Ok(flac::MinSaving::Percent(percent))
+            }
+            _ => Err(format!("invalid percentage: {value}")),
+        }
+    } else {
+        parse_size(value).map(flac::MinSaving::Bytes)
+    }
+}
+
+fn parse_trial(value: &str) -> Result<flac::EncoderSettings, String> {
+    let (level, apodization) = match value.trim().split_once(':') {
+        Some((level, apodization)) if !apodization.is_empty() => {
+            (level, Some(apodization.to_string()))
+        }
+        Some(_) => return Err(format!("missing apodization: {value}")),
+        None => (value.trim(), None),
+    };
+    let (level, exhaustive) = match level.strip_suffix('e') {
+        Some(level) => (level, true),
+        None => (level, false),
+    };
+    match level.parse::<u32>() {
+        Ok(level) if level <= 8 => Ok(flac::EncoderSettings {
+            level,
+            exhaustive,
+            apodization,
+        }),
+        _ => Err(format!("invalid compression level: {value}")),
+    }
+}
+
+fn parse_rename(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("invalid rename, expected OLD=NEW: {value}")),
+    }
+}
+
+fn tag_policy(args: &ArgMatches) -> tags::TagPolicy {
+    let mut policy = tags::TagPolicy::default();
+    if let Some(strip) = args.get_many::<String>("strip_tag") {
+        policy.strip.extend(strip.cloned());
+    }
+    if let Some(keep) = args.get_many::<String>("keep_tag") {
+        policy.keep.extend(keep.cloned());
+    }
+    if let Some(renames) = args.get_many::<(String, String)>("rename_tag") {
+        policy.renames.extend(renames.cloned());
+    }
+    policy.uppercase = args.get_flag("uppercase_tags");
+    policy.trim = args.get_flag("trim_tags");
+    policy.dedupe = args.get_flag("dedupe_tags");
+    policy.drop_empty = args.get_flag("drop_empty_tags");
+    policy
+}
+
+/// Encoder picked by `--flac-binary` or `--encoder`
+fn encoder_backend(args: &ArgMatches) -> flac::EncoderBackend {
+    match args.get_one::<PathBuf>("flac_binary") {
+        Some(binary) => flac::EncoderBackend::External(binary.clone()),
+        None => args
+            .get_one::<flac::EncoderBackend>("encoder")
+            .cloned()
+            .unwrap_or_default(),
+    }
+}
+
+fn encode_options(
+    args: &ArgMatches,
+    policy: &tags::TagPolicy,
+    picture_policy: &pictures::PicturePolicy,
+) -> flac::EncodeOptions {
+    flac::EncodeOptions {
+        read_limit: args
+            .get_one::<u64>("read_limit")
+            .map(|&limit| Arc::new(throttle::Throttle::new(limit))),
+        write_limit: args
+            .get_one::<u64>("write_limit")
+            .map(|&limit| Arc::new(throttle::Throttle::new(limit))),
+        tags: policy.clone(),
+        seektable: flac::SeekTablePolicy {
+            spacing: *args
+                .get_one::<flac::SeekSpacing>("seektable_spacing")
+                .unwrap(),
+            add_missing: args.get_flag("add_seektable"),
+        },
+        padding: args
+            .get_one::<flac::PaddingPolicy>("padding")
+            .copied()
+            .unwrap_or_default(),
+        pictures: picture_policy.clone(),
+        depth: *args.get_one::<flac::DepthPolicy>("bit_depth").unwrap(),
+        collapse_dual_mono: args.get_flag("collapse_dual_mono"),
+        ogg: *args.get_one::<flac::OggPolicy>("ogg").unwrap(),
+        encoder: encoder_backend(args),
+        trials: args
+            .get_many::<flac::EncoderSettings>("trial")
+            .map(|trials| trials.cloned().collect())
+            .unwrap_or_default(),
+        min_saving: args.get_one::<flac::MinSaving>("min_saving").copied(),
+        update_vendor: args.get_flag("update_vendor"),
+        vendor: args.get_one::<String>("target_vendor").cloned(),
+    }
+}
+
+fn picture_policy(args: &ArgMatches) -> pictures::PicturePolicy {
+    pictures::PicturePolicy {
+        strip: args.get_flag("strip_pictures"),
+        front_only: args.get_flag("front_cover_only"),
+        extract: args.get_flag("extract_cover"),
+        dedupe: args.get_flag("dedupe_pictures"),
+        max_size: args.get_one::<u64>("max_picture_size").copied(),
+        drop_oversized: args.get_flag("drop_oversized"),
+    }
+}
+
 fn print_completions<G: Generator>(generator: G, cmd: &mut Command) {
     generate(
         generator,
@@ -83,26 +729,106 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    // the pure-Rust encoder has a single setting
+    if encoder_backend(&args) == flac::EncoderBackend::Rust
+        && let Some(trial) = args
+            .get_many::<flac::EncoderSettings>("trial")
+            .into_iter()
+            .flatten()
+            .find(|trial| **trial != flac::EncoderSettings::default())
+    {
+        build_cli()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("--trial {trial} needs the libflac or an external encoder"),
+            )
+            .exit();
+    }
+
+    throttle::lower_priority(
+        args.get_one::<i32>("nice").copied(),
+        args.get_flag("idle_io"),
+    )?;
+
+    // first signal stops scheduling new work, the second one aborts running encodes
     let running = Arc::new(AtomicBool::new(true));
+    let encoding = Arc::new(AtomicBool::new(true));
     let r = running.clone();
+    let e = encoding.clone();
 
     ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
+        if r.swap(false, Ordering::SeqCst) {
+            eprintln!("Stopping after running encodes finish, signal again to abort them");
+        } else {
+            e.store(false, Ordering::SeqCst);
+        }
     })?;
 
     let conn = db::init_connection(args.get_one::<PathBuf>("db"))?;
 
     let path = args.get_one::<PathBuf>("path");
 
-    if path.is_none() && !args.get_flag("clean") && !args.get_flag("doit") {
+    let policy = tag_policy(&args);
+    let picture_policy = picture_policy(&args);
+
+    if path.is_none()
+        && !args.get_flag("clean")
+        && !args.get_flag("doit")
+        && !args.get_flag("retag")
+        && !args.get_flag("pictures")
+        && !args.get_flag("detect_lossy")
+        && !args.get_flag("audit")
+        && !args.get_flag("missing_md5")
+        && !args.get_flag("dupes")
+        && !args.get_flag("convert")
+    {
         let count = db::get_toencode_number(&conn)?;
         println!("Files to reencode:\t{}", style(count).green());
+        let count = db::get_toretag_number(&conn, &policy.fingerprint())?;
+        println!("Files to retag:\t\t{}", style(count).green());
         return Ok(());
     }
 
+    let run = db::RunLock::acquire(args.get_one::<PathBuf>("db"), args.get_flag("shared"))?;
+
+    let encode = encode_options(&args, &policy, &picture_policy);
+    // asking an external encoder for its vendor runs it, so only do so when something needs it
+    let needs_vendor = path.is_some()
+        || args.get_flag("convert")
+        || args.get_flag("retag_vendor")
+        || args.get_flag("doit");
+    let encoder_vendor = if args.get_flag("convert") || (needs_vendor && encode.vendor.is_none()) {
+        encode.encoder.vendor()?
+    } else {
+        String::new()
+    };
+    let target_vendor = encode
+        .vendor
+        .clone()
+        .unwrap_or_else(|| encoder_vendor.clone());
+
     if let Some(realpath) = path {
         let hanlder = running.clone();
-        files::index_files_recursively(realpath, &conn, hanlder)?;
+        files::index_files_recursively(
+            realpath,
+            &conn,
+            hanlder,
+            args.get_flag("convert"),
+            &target_vendor,
+        )?;
+    }
+
+    if args.get_flag("convert") {
+        let handler = running.clone();
+        files::convert_files(
+            &conn,
+            handler,
+            encoding.clone(),
+            &encode,
+            args.get_flag("remove_sources"),
+            &encoder_vendor,
+            run.owner(),
+        )?;
     }
 
     if args.get_flag("clean") {
@@ -110,10 +836,81 @@ fn main() -> Result<()> {
         files::clean_files(&conn, handler)?;
     }
 
+    if args.get_flag("retag") {
+        let handler = running.clone();
+        let vendor = args
+            .get_flag("retag_vendor")
+            .then_some(target_vendor.as_str());
+        files::retag_files(&conn, handler, &policy, vendor, run.owner())?;
+    }
+
+    if args.get_flag("detect_lossy") {
+        let handler = running.clone();
+        files::detect_lossy_files(&conn, handler)?;
+        files::lossy_report(&conn, *args.get_one::<f64>("lossy_threshold").unwrap())?;
+    }
+
+    if args.get_flag("audit") {
+        let handler = running.clone();
+        let deadline = run_deadline(
+            args.get_one::<u64>("until").copied(),
+            args.get_one::<Duration>("max_duration").copied(),
+        )?;
+        files::audit_files(
+            &conn,
+            handler,
+            args.get_one::<Duration>("audit_age").copied(),
+            deadline,
+        )?;
+        files::audit_report(&conn)?;
+    }
+
+    if args.get_flag("missing_md5") {
+        files::md5_report(&conn)?;
+    }
+
+    if args.get_flag("dupes") {
+        let handler = running.clone();
+        files::dupes_report(
+            &conn,
+            handler,
+            args.get_one::<files::DupeScript>("dupes_script").copied(),
+        )?;
+    }
+
+    if args.get_flag("pictures") {
+        if picture_policy.is_noop() {
+            return Err(anyhow::anyhow!(
+                "--pictures needs at least one picture option"
+            ));
+        }
+        let handler = running.clone();
+        files::picture_files(&conn, handler, &picture_policy, run.owner())?;
+    }
+
     if args.get_flag("doit") {
+        let flagged = db::flag_vendor_mismatch(&conn, &target_vendor)?;
+        if flagged > 0 {
+            println!(
+                "Files queued for another vendor:\t{}",
+                style(flagged).green()
+            );
+        }
         let hanlder = running.clone();
-        let threads = *args.get_one::<usize>("threads").unwrap();
-        files::reencode_files(conn, hanlder, threads)?;
+        let options = files::RunOptions {
+            threads: *args.get_one::<usize>("threads").unwrap(),
+            order: *args.get_one::<db::Order>("order").unwrap(),
+            limit: args.get_one::<files::Limit>("limit").copied(),
+            deadline: run_deadline(
+                args.get_one::<u64>("until").copied(),
+                args.get_one::<Duration>("max_duration").copied(),
+            )?,
+            max_bytes: args.get_one::<u64>("max_bytes").copied(),
+            max_load: args.get_one::<f64>("max_load").copied(),
+            hardlinks: *args.get_one::<files::HardlinkPolicy>("hardlinks").unwrap(),
+            encode,
+        };
+        files::reencode_files(conn, hanlder, encoding.clone(), &options, run.owner())?;
     }
     Ok::<(), anyhow::Error>(())
 }
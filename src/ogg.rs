@@ -0,0 +1,378 @@
+use anyhow::{Result, anyhow};
+use flac_codec::stream::FrameIterator;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const CAPTURE: &[u8; 4] = b"OggS";
+/// Start of the first packet of an Ogg FLAC stream
+const MAPPING: &[u8; 5] = b"\x7FFLAC";
+/// Audio pages are closed once their payload grows past this
+const PAGE_TARGET: usize = 8192;
+
+const STREAMINFO: u8 = 0;
+const SEEKTABLE: u8 = 3;
+const VORBIS_COMMENT: u8 = 4;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// Returns `true` for files that may hold an Ogg stream, judging by the extension
+pub(crate) fn is_ogg(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("oga") || extension.eq_ignore_ascii_case("ogg")
+        })
+}
+
+/// Returns `true` if the file is an Ogg file carrying FLAC, rather than Vorbis or Opus
+pub(crate) fn is_ogg_flac(path: &Path) -> bool {
+    File::open(path)
+        .ok()
+        .and_then(|file| read_page(&mut BufReader::new(file)).ok().flatten())
+        .is_some_and(|page| page.body.starts_with(MAPPING))
+}
+
+struct Page {
+    header_type: u8,
+    serial: u32,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+/// Reads the next page, `None` at the end of the file
+fn read_page(reader: &mut impl Read) -> Result<Option<Page>> {
+    let mut header = [0u8; 27];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    if &header[..4] != CAPTURE || header[4] != 0 {
+        return Err(anyhow!("lost Ogg page sync"));
+    }
+    let mut segments = vec![0; usize::from(header[26])];
+    reader.read_exact(&mut segments)?;
+    let mut body = vec![0; segments.iter().map(|lacing| usize::from(*lacing)).sum()];
+    reader.read_exact(&mut body)?;
+
+    let crc = u32::from_le_bytes(header[22..26].try_into().unwrap());
+    header[22..26].fill(0);
+    let mut page = header.to_vec();
+    page.extend(&segments);
+    page.extend(&body);
+    if crc32(&page) != crc {
+        return Err(anyhow!("Ogg page checksum mismatch"));
+    }
+
+    Ok(Some(Page {
+        header_type: header[5],
+        serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+        segments,
+        body,
+    }))
+}
+
+/// Reassembles the packets of the first FLAC logical stream, skipping any others
+struct PacketReader<R> {
+    reader: R,
+    serial: Option<u32>,
+    packets: VecDeque<Vec<u8>>,
+    partial: Vec<u8>,
+}
+
+impl<R: Read> PacketReader<R> {
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        while self.packets.is_empty() {
+            let Some(page) = read_page(&mut self.reader)? else {
+                return Ok(None);
+            };
+            match self.serial {
+                None if page.header_type & 0x02 != 0 && page.body.starts_with(MAPPING) => {
+                    self.serial = Some(page.serial)
+                }
+                Some(serial) if serial == page.serial => {}
+                _ => continue,
+            }
+            if page.header_type & 0x01 == 0 {
+                self.partial.clear();
+            }
+            let mut position = 0;
+            for lacing in page.segments {
+                let end = position + usize::from(lacing);
+                self.partial.extend_from_slice(&page.body[position..end]);
+                position = end;
+                if lacing < 255 {
+                    self.packets.push_back(std::mem::take(&mut self.partial));
+                }
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+}
+
+/// Presents an Ogg FLAC stream as the native FLAC stream it encapsulates
+pub(crate) struct OggFlacReader<R> {
+    packets: PacketReader<R>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> OggFlacReader<R> {
+    pub(crate) fn new(reader: R) -> Result<Self> {
+        let mut packets = PacketReader {
+            reader,
+            serial: None,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+        };
+        let Some(first) = packets.next_packet()? else {
+            return Err(anyhow!("no FLAC stream in Ogg file"));
+        };
+        // mapping header, version, header packet count, then the native signature and STREAMINFO
+        if first.len() < 13 + 38 || first[5] != 1 || &first[9..13] != b"fLaC" {
+            return Err(anyhow!("unsupported Ogg FLAC mapping"));
+        }
+        Ok(OggFlacReader {
+            packets,
+            buffer: first[9..].to_vec(),
+            position: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for OggFlacReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.packets.next_packet() {
+                Ok(Some(packet)) => {
+                    self.buffer = packet;
+                    self.position = 0;
+                }
+                Ok(None) => return Ok(0),
+                Err(error) => return Err(std::io::Error::other(error)),
+            }
+        }
+        let length = buf.len().min(self.buffer.len() - self.position);
+        buf[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// Splits packets into pages of a single logical stream
+struct PageWriter<W> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    first: bool,
+    continued: bool,
+    /// Granule position of the last packet finished on the current page
+    granule: Option<u64>,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl<W: Write> PageWriter<W> {
+    fn write_packet(&mut self, packet: &[u8], granule: u64) -> Result<()> {
+        let mut position = 0;
+        loop {
+            if self.segments.len() == 255 {
+                self.flush(false)?;
+                self.continued = position > 0;
+            }
+            let length = (packet.len() - position).min(255);
+            self.segments.push(length as u8);
+            self.body
+                .extend_from_slice(&packet[position..position + length]);
+            position += length;
+            // a lacing value below 255 ends the packet
+            if length < 255 {
+                self.granule = Some(granule);
+                return Ok(());
+            }
+        }
+    }
+
+    fn flush(&mut self, last: bool) -> Result<()> {
+        let header_type =
+            u8::from(self.continued) | (u8::from(self.first) << 1) | (u8::from(last) << 2);
+        let mut page = CAPTURE.to_vec();
+        page.push(0);
+        page.push(header_type);
+        // pages finishing no packet carry a granule position of -1
+        page.extend(self.granule.unwrap_or(u64::MAX).to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(self.segments.len() as u8);
+        page.extend(&self.segments);
+        page.extend(&self.body);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&page)?;
+
+        self.sequence += 1;
+        self.first = false;
+        self.continued = false;
+        self.granule = None;
+        self.segments.clear();
+        self.body.clear();
+        Ok(())
+    }
+}
+
+/// Rewrites the native FLAC file at `source` as an Ogg FLAC file at `target`.
+///
+/// Seektables are dropped, since Ogg is seeked through its granule positions.
+pub(crate) fn remux(source: &Path, target: &Path) -> Result<()> {
+    // frame boundaries can only be found by parsing the frames
+    let frames = FrameIterator::open(source)?
+        .map(|frame| {
+            frame.map(|(frame, offset)| (offset, u64::from(u16::from(frame.header.block_size))))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut signature = [0; 4];
+    reader.read_exact(&mut signature)?;
+    if &signature != b"fLaC" {
+        return Err(anyhow!("not a native FLAC file"));
+    }
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; length];
+        reader.read_exact(&mut data)?;
+        let kind = header[0] & 0x7F;
+        if kind != SEEKTABLE {
+            blocks.push((kind, data));
+        }
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    // the mapping wants the comment right after STREAMINFO
+    blocks.sort_by_key(|(kind, _)| match *kind {
+        STREAMINFO => 0,
+        VORBIS_COMMENT => 1,
+        _ => 2,
+    });
+    let count = blocks.len();
+    let blocks = blocks
+        .into_iter()
+        .enumerate()
+        .map(|(index, (kind, data))| {
+            let last = if index + 1 == count { 0x80 } else { 0 };
+            let mut block = vec![kind | last];
+            block.extend(&(data.len() as u32).to_be_bytes()[1..]);
+            block.extend(data);
+            block
+        })
+        .collect::<Vec<_>>();
+
+    let serial = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos() ^ time.as_secs() as u32);
+    let mut writer = PageWriter {
+        writer: BufWriter::new(File::create(target)?),
+        serial,
+        sequence: 0,
+        first: true,
+        continued: false,
+        granule: None,
+        segments: Vec::new(),
+        body: Vec::new(),
+    };
+
+    let mut first = MAPPING.to_vec();
+    first.extend([1, 0]);
+    first.extend((count as u16 - 1).to_be_bytes());
+    first.extend(b"fLaC");
+    first.extend(&blocks[0]);
+    writer.write_packet(&first, 0)?;
+    writer.flush(false)?;
+    for block in &blocks[1..] {
+        writer.write_packet(block, 0)?;
+    }
+    if !writer.segments.is_empty() {
+        writer.flush(false)?;
+    }
+
+    let mut samples = 0;
+    let mut frame = Vec::new();
+    for (index, (offset, block_size)) in frames.iter().enumerate() {
+        if writer.body.len() >= PAGE_TARGET {
+            writer.flush(false)?;
+        }
+        frame.clear();
+        match frames.get(index + 1) {
+            Some((next, _)) => {
+                frame.resize((next - offset) as usize, 0);
+                reader.read_exact(&mut frame)?;
+            }
+            None => {
+                reader.read_to_end(&mut frame)?;
+            }
+        }
+        samples += block_size;
+        writer.write_packet(&frame, samples)?;
+    }
+    writer.flush(true)?;
+    writer.writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flac_codec::decode::{Verified, verify_reader};
+
+    #[test]
+    fn crc() {
+        assert!(crc32(b"123456789") == 0x89A1_897F);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let target = Path::new("./samples/roundtrip.oga");
+        remux(Path::new("./samples/24bit.flac"), target).unwrap();
+        let flac = is_ogg_flac(target);
+        let verified = OggFlacReader::new(BufReader::new(File::open(target).unwrap()))
+            .map(|reader| matches!(verify_reader(reader), Ok(Verified::MD5Match)));
+        std::fs::remove_file(target).unwrap();
+        assert!(flac && verified.unwrap());
+    }
+}
@@ -0,0 +1,594 @@
+use crate::id3;
+use anyhow::{Result, anyhow};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Sample frames converted per [`PcmReader::fill_buf`] call
+const FRAMES_PER_READ: usize = 4096;
+
+/// RIFF INFO chunks and the Vorbis comment fields they map to
+const INFO_FIELDS: [(&[u8; 4], &str); 9] = [
+    (b"INAM", "TITLE"),
+    (b"IART", "ARTIST"),
+    (b"IPRD", "ALBUM"),
+    (b"ICRD", "DATE"),
+    (b"IGNR", "GENRE"),
+    (b"ICMT", "COMMENT"),
+    (b"ICOP", "COPYRIGHT"),
+    (b"ITRK", "TRACKNUMBER"),
+    (b"IPRT", "TRACKNUMBER"),
+];
+
+/// AIFF text chunks and the Vorbis comment fields they map to
+const AIFF_FIELDS: [(&[u8; 4], &str); 4] = [
+    (b"NAME", "TITLE"),
+    (b"AUTH", "ARTIST"),
+    (b"ANNO", "COMMENT"),
+    (b"(c) ", "COPYRIGHT"),
+];
+
+const W64_RIFF: [u8; 16] = *b"riff\x2E\x91\xCF\x11\xA5\xD6\x28\xDB\x04\xC1\x00\x00";
+const W64_WAVE: [u8; 16] = *b"wave\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+const W64_FMT: [u8; 16] = *b"fmt \xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+const W64_DATA: [u8; 16] = *b"data\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+
+/// Returns `true` for files the converter can read, judging by the extension
+pub(crate) fn is_pcm(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ["wav", "wave", "aif", "aiff", "aifc", "w64"]
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+}
+
+/// Shape of the audio in an uncompressed container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PcmFormat {
+    pub(crate) channels: u32,
+    pub(crate) bits_per_sample: u32,
+    pub(crate) sample_rate: u32,
+    /// Sample frames in the data chunk
+    pub(crate) total_samples: u64,
+}
+
+/// How samples are laid out in the data chunk
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Bytes each sample takes, at least enough for `bits_per_sample`
+    container: usize,
+    big_endian: bool,
+    /// 8 bit WAV samples are stored with an offset of 128
+    unsigned: bool,
+}
+
+/// Reads interleaved integer samples out of a WAV, AIFF or W64 file
+pub(crate) struct PcmReader<R> {
+    inner: R,
+    pub(crate) format: PcmFormat,
+    /// Tags found in the container, mapped to Vorbis comment fields
+    pub(crate) tags: Vec<(String, String)>,
+    layout: Layout,
+    /// Bytes of the data chunk not read yet
+    remaining: u64,
+    raw: Vec<u8>,
+    samples: Vec<i32>,
+    consumed: usize,
+}
+
+/// What the container parsers find before the samples are read
+#[derive(Default)]
+struct Header {
+    format: Option<(PcmFormat, Layout)>,
+    data: Option<(u64, u64)>,
+    tags: Vec<(String, String)>,
+}
+
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Option<[u8; N]> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer).ok()?;
+    Some(buffer)
+}
+
+/// Reads a chunk body of `size` bytes and skips the padding up to `padded`
+fn read_body(reader: &mut impl Read, size: u64, padded: u64) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    reader.take(size).read_to_end(&mut body)?;
+    if (body.len() as u64) < size {
+        return Err(anyhow!("truncated chunk"));
+    }
+    // writers often leave out the padding after the last chunk
+    std::io::copy(&mut reader.take(padded - size), &mut std::io::sink())?;
+    Ok(body)
+}
+
+/// Parses a WAVEFORMAT(EX|EXTENSIBLE) structure, shared by WAV and W64
+fn parse_fmt(body: &[u8]) -> Result<(PcmFormat, Layout)> {
+    if body.len() < 16 {
+        return Err(anyhow!("truncated fmt chunk"));
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+    let mut tag = u16_at(0);
+    let channels = u32::from(u16_at(2));
+    let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+    let block_align = usize::from(u16_at(12));
+    let mut bits_per_sample = u32::from(u16_at(14));
+    if tag == 0xFFFE && body.len() >= 26 {
+        let valid = u32::from(u16_at(18));
+        if valid != 0 {
+            bits_per_sample = valid;
+        }
+        tag = u16_at(24);
+    }
+    match tag {
+        1 => {}
+        3 => return Err(anyhow!("floating point audio can't be stored in FLAC")),
+        other => return Err(anyhow!("unsupported WAV format {other:#x}")),
+    }
+    if channels == 0 {
+        return Err(anyhow!("no channels"));
+    }
+    let container = (block_align / channels as usize).max(bits_per_sample.div_ceil(8) as usize);
+    Ok((
+        PcmFormat {
+            channels,
+            bits_per_sample,
+            sample_rate,
+            total_samples: 0,
+        },
+        Layout {
+            container,
+            big_endian: false,
+            unsigned: bits_per_sample <= 8,
+        },
+    ))
+}
+
+fn parse_info(body: &[u8], tags: &mut Vec<(String, String)>) {
+    let mut position = 4;
+    while position + 8 <= body.len() {
+        let id = &body[position..position + 4];
+        let size =
+            u32::from_le_bytes(body[position + 4..position + 8].try_into().unwrap()) as usize;
+        position += 8;
+        let Some(value) = body.get(position..position + size) else {
+            break;
+        };
+        position += size + (size & 1);
+        if let Some((_, name)) = INFO_FIELDS.iter().find(|(info, _)| &info[..] == id) {
+            let value = text(value);
+            if !value.is_empty() {
+                tags.push((name.to_string(), value));
+            }
+        }
+    }
+}
+
+fn parse_wav<R: Read + Seek>(reader: &mut R) -> Result<Header> {
+    let Some([_, _, _, _, w, a, v, e]) = read_array::<8>(reader) else {
+        return Err(anyhow!("truncated RIFF header"));
+    };
+    if &[w, a, v, e] != b"WAVE" {
+        return Err(anyhow!("not a WAVE file"));
+    }
+
+    let mut header = Header::default();
+    while let Some(chunk) = read_array::<8>(reader) {
+        let id = &chunk[..4];
+        let size = u64::from(u32::from_le_bytes(chunk[4..].try_into().unwrap()));
+        let padded = size + (size & 1);
+        match id {
+            b"fmt " => header.format = Some(parse_fmt(&read_body(reader, size, padded)?)?),
+            b"data" => {
+                let start = reader.stream_position()?;
+                let end = reader.seek(SeekFrom::End(0))?;
+                // streamed files leave the size at 0 or the maximum
+                let size = if size == 0 || size == u64::from(u32::MAX) {
+                    end - start
+                } else {
+                    size.min(end - start)
+                };
+                header.data = Some((start, size));
+                reader.seek(SeekFrom::Start((start + size + (size & 1)).min(end)))?;
+            }
+            b"LIST" => {
+                let body = read_body(reader, size, padded)?;
+                if body.starts_with(b"INFO") {
+                    parse_info(&body, &mut header.tags);
+                }
+            }
+            b"id3 " | b"ID3 " => header
+                .tags
+                .extend(id3::parse(&read_body(reader, size, padded)?)),
+            _ => {
+                reader.seek(SeekFrom::Current(padded as i64))?;
+            }
+        }
+    }
+    Ok(header)
+}
+
+/// Converts an 80 bit IEEE 754 extended float, as AIFF stores its sample rate
+fn extended_to_u32(bytes: [u8; 10]) -> u32 {
+    let exponent = i32::from(u16::from_be_bytes([bytes[0] & 0x7F, bytes[1]]));
+    let mantissa = u64::from_be_bytes(bytes[2..].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0;
+    }
+    (mantissa as f64 * 2f64.powi(exponent - 16383 - 63)).round() as u32
+}
+
+fn parse_aiff<R: Read + Seek>(reader: &mut R) -> Result<Header> {
+    let Some([_, _, _, _, a, i, f, kind]) = read_array::<8>(reader) else {
+        return Err(anyhow!("truncated FORM header"));
+    };
+    let compressed = match &[a, i, f, kind] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(anyhow!("not an AIFF file")),
+    };
+
+    let mut header = Header::default();
+    while let Some(chunk) = read_array::<8>(reader) {
+        let id = &chunk[..4];
+        let size = u64::from(u32::from_be_bytes(chunk[4..].try_into().unwrap()));
+        let padded = size + (size & 1);
+        match id {
+            b"COMM" => {
+                let body = read_body(reader, size, padded)?;
+                if body.len() < 18 {
+                    return Err(anyhow!("truncated COMM chunk"));
+                }
+                let channels = u32::from(u16::from_be_bytes([body[0], body[1]]));
+                let total_samples = u64::from(u32::from_be_bytes(body[2..6].try_into().unwrap()));
+                let bits_per_sample = u32::from(u16::from_be_bytes([body[6], body[7]]));
+                let sample_rate = extended_to_u32(body[8..18].try_into().unwrap());
+                let big_endian = match body.get(18..22) {
+                    Some(b"sowt") if compressed => false,
+                    Some(b"NONE" | b"twos") | None => true,
+                    Some(_) if !compressed => true,
+                    Some(other) => {
+                        return Err(anyhow!(
+                            "unsupported AIFC compression {}",
+                            String::from_utf8_lossy(other)
+                        ));
+                    }
+                };
+                header.format = Some((
+                    PcmFormat {
+                        channels,
+                        bits_per_sample,
+                        sample_rate,
+                        total_samples,
+                    },
+                    Layout {
+                        container: bits_per_sample.div_ceil(8) as usize,
+                        big_endian,
+                        unsigned: false,
+                    },
+                ));
+            }
+            b"SSND" => {
+                let Some(offsets) = read_array::<8>(reader) else {
+                    return Err(anyhow!("truncated SSND chunk"));
+                };
+                let offset = u64::from(u32::from_be_bytes(offsets[..4].try_into().unwrap()));
+                let start = reader.stream_position()? + offset;
+                let end = reader.seek(SeekFrom::End(0))?;
+                let size = size
+                    .saturating_sub(8 + offset)
+                    .min(end.saturating_sub(start));
+                header.data = Some((start, size));
+                reader.seek(SeekFrom::Start((start - offset + padded - 8).min(end)))?;
+            }
+            b"ID3 " | b"id3 " => header
+                .tags
+                .extend(id3::parse(&read_body(reader, size, padded)?)),
+            _ => {
+                if let Some((_, name)) = AIFF_FIELDS.iter().find(|(chunk, _)| &chunk[..] == id) {
+                    let value = text(&read_body(reader, size, padded)?);
+                    if !value.is_empty() {
+                        header.tags.push((name.to_string(), value));
+                    }
+                } else {
+                    reader.seek(SeekFrom::Current(padded as i64))?;
+                }
+            }
+        }
+    }
+    Ok(header)
+}
+
+fn parse_w64<R: Read + Seek>(reader: &mut R) -> Result<Header> {
+    // the first four bytes of the GUID were already read
+    let Some(riff) = read_array::<20>(reader) else {
+        return Err(anyhow!("truncated W64 header"));
+    };
+    if riff[..12] != W64_RIFF[4..] || read_array::<16>(reader) != Some(W64_WAVE) {
+        return Err(anyhow!("not a W64 file"));
+    }
+
+    let mut header = Header::default();
+    while let Some(chunk) = read_array::<24>(reader) {
+        let guid: [u8; 16] = chunk[..16].try_into().unwrap();
+        // sizes include the chunk header, and chunks are aligned to 8 bytes
+        let size = u64::from_le_bytes(chunk[16..].try_into().unwrap()).saturating_sub(24);
+        let padded = size.next_multiple_of(8);
+        match guid {
+            W64_FMT => header.format = Some(parse_fmt(&read_body(reader, size, padded)?)?),
+            W64_DATA => {
+                let start = reader.stream_position()?;
+                let end = reader.seek(SeekFrom::End(0))?;
+                header.data = Some((start, size.min(end - start)));
+                reader.seek(SeekFrom::Start((start + padded).min(end)))?;
+            }
+            _ => {
+                reader.seek(SeekFrom::Current(padded as i64))?;
+            }
+        }
+    }
+    Ok(header)
+}
+
+impl<R: Read + Seek> PcmReader<R> {
+    /// Parses the container and positions `inner` at the start of the samples.
+    pub(crate) fn new(mut inner: R) -> Result<Self> {
+        let header = match read_array::<4>(&mut inner).as_ref() {
+            Some(b"RIFF") => parse_wav(&mut inner)?,
+            Some(b"FORM") => parse_aiff(&mut inner)?,
+            Some(b"riff") => parse_w64(&mut inner)?,
+            _ => return Err(anyhow!("unknown container")),
+        };
+        let Some((mut format, layout)) = header.format else {
+            return Err(anyhow!("missing format chunk"));
+        };
+        let Some((start, size)) = header.data else {
+            return Err(anyhow!("missing sample data"));
+        };
+        // FLAC holds up to 8 channels, and samples are unpacked into 32 bit words
+        if !(1..=8).contains(&format.channels)
+            || !(4..=32).contains(&format.bits_per_sample)
+            || !(1..=4).contains(&layout.container)
+            || layout.container as u32 * 8 < format.bits_per_sample
+        {
+            return Err(anyhow!(
+                "unsupported layout of {} channels at {} bits in {} byte containers",
+                format.channels,
+                format.bits_per_sample,
+                layout.container
+            ));
+        }
+
+        let frame = layout.container as u64 * u64::from(format.channels);
+        // a partial frame at the end can't be encoded
+        let remaining = size - size % frame;
+        format.total_samples = remaining / frame;
+        inner.seek(SeekFrom::Start(start))?;
+
+        Ok(PcmReader {
+            inner,
+            format,
+            tags: header.tags,
+            layout,
+            remaining,
+            raw: Vec::new(),
+            samples: Vec::new(),
+            consumed: 0,
+        })
+    }
+
+    /// Returns the next interleaved samples, empty once the data chunk is exhausted.
+    pub(crate) fn fill_buf(&mut self) -> Result<&[i32]> {
+        if self.consumed < self.samples.len() {
+            return Ok(&self.samples[self.consumed..]);
+        }
+        self.samples.clear();
+        self.consumed = 0;
+
+        let Layout {
+            container,
+            big_endian,
+            unsigned,
+        } = self.layout;
+        let frame = container * self.format.channels as usize;
+        let wanted = ((FRAMES_PER_READ * frame) as u64).min(self.remaining) as usize;
+        self.raw.resize(wanted, 0);
+        self.inner.read_exact(&mut self.raw)?;
+        self.remaining -= wanted as u64;
+
+        // samples are left justified in their container
+        let shift = container as u32 * 8 - self.format.bits_per_sample;
+        self.samples
+            .extend(self.raw.chunks_exact(container).map(|bytes| {
+                let mut word = [0u8; 4];
+                if big_endian {
+                    word[..container].copy_from_slice(bytes);
+                } else {
+                    for (index, byte) in bytes.iter().enumerate() {
+                        word[container - 1 - index] = *byte;
+                    }
+                }
+                if unsigned {
+                    word[0] ^= 0x80;
+                }
+                (i32::from_be_bytes(word) >> (32 - container as u32 * 8)) >> shift
+            }));
+        Ok(&self.samples)
+    }
+
+    pub(crate) fn consume(&mut self, amount: usize) {
+        self.consumed = (self.consumed + amount).min(self.samples.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((body.len() as u32).to_le_bytes());
+        chunk.extend(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn read_all<R: Read + Seek>(reader: &mut PcmReader<R>) -> Vec<i32> {
+        let mut samples = Vec::new();
+        loop {
+            let buf = reader.fill_buf().unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            samples.extend_from_slice(buf);
+            let length = buf.len();
+            reader.consume(length);
+        }
+        samples
+    }
+
+    #[test]
+    fn wav_24bit_with_trailing_info() {
+        let mut fmt = 1u16.to_le_bytes().to_vec();
+        fmt.extend(2u16.to_le_bytes());
+        fmt.extend(48000u32.to_le_bytes());
+        fmt.extend((48000u32 * 6).to_le_bytes());
+        fmt.extend(6u16.to_le_bytes());
+        fmt.extend(24u16.to_le_bytes());
+        let data = [
+            0x01, 0x00, 0x80, 0xFF, 0xFF, 0x7F, 0x02, 0x00, 0x00, 0xFE, 0xFF, 0xFF,
+        ];
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"INAM", b"Song\0"));
+        info.extend(chunk(b"IART", b"Band\0"));
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"data", &data));
+        body.extend(chunk(b"LIST", &info));
+        let file = chunk(b"RIFF", &body);
+
+        let mut reader = PcmReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.format.bits_per_sample == 24 && reader.format.total_samples == 2);
+        assert!(read_all(&mut reader) == [-8388607, 8388607, 2, -2]);
+        assert!(
+            reader.tags.len() == 2 && reader.tags[0] == ("TITLE".to_string(), "Song".to_string())
+        );
+    }
+
+    #[test]
+    fn wav_unpadded_last_chunk() {
+        let mut fmt = 1u16.to_le_bytes().to_vec();
+        fmt.extend(1u16.to_le_bytes());
+        fmt.extend(44100u32.to_le_bytes());
+        fmt.extend((44100u32 * 2).to_le_bytes());
+        fmt.extend(2u16.to_le_bytes());
+        fmt.extend(16u16.to_le_bytes());
+        let mut info = b"INFOINAM".to_vec();
+        info.extend(3u32.to_le_bytes());
+        info.extend(b"Ab\0");
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"data", &[0x01, 0x00]));
+        // odd sized and missing its pad byte
+        body.extend(b"LIST");
+        body.extend((info.len() as u32).to_le_bytes());
+        body.extend(info);
+        let file = chunk(b"RIFF", &body);
+
+        let mut reader = PcmReader::new(Cursor::new(file)).unwrap();
+        assert!(read_all(&mut reader) == [1]);
+        assert!(reader.tags == [("TITLE".to_string(), "Ab".to_string())]);
+    }
+
+    #[test]
+    fn malformed_fmt() {
+        let wav = |channels: u16, block_align: u16, bits: u16| {
+            let mut fmt = 1u16.to_le_bytes().to_vec();
+            fmt.extend(channels.to_le_bytes());
+            fmt.extend(44100u32.to_le_bytes());
+            fmt.extend((44100u32 * u32::from(block_align)).to_le_bytes());
+            fmt.extend(block_align.to_le_bytes());
+            fmt.extend(bits.to_le_bytes());
+            let mut body = b"WAVE".to_vec();
+            body.extend(chunk(b"fmt ", &fmt));
+            body.extend(chunk(b"data", &[0; 64]));
+            PcmReader::new(Cursor::new(chunk(b"RIFF", &body)))
+        };
+        assert!(wav(2, 4, 16).is_ok());
+        // 8 byte containers
+        assert!(wav(2, 16, 16).is_err());
+        assert!(wav(2, 4, 40).is_err());
+        assert!(wav(2, 4, 2).is_err());
+        assert!(wav(9, 18, 16).is_err());
+    }
+
+    #[test]
+    fn aiff_16bit() {
+        let mut comm = 1u16.to_be_bytes().to_vec();
+        comm.extend(3u32.to_be_bytes());
+        comm.extend(16u16.to_be_bytes());
+        // 44100 as an 80 bit extended float
+        comm.extend([0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+        let mut ssnd = vec![0; 8];
+        ssnd.extend([0x00, 0x01, 0xFF, 0xFF, 0x7F, 0xFF]);
+
+        let mut body = b"AIFF".to_vec();
+        for (id, data) in [
+            (b"COMM", comm),
+            (b"SSND", ssnd),
+            (b"NAME", b"Song".to_vec()),
+        ] {
+            body.extend(id);
+            body.extend((data.len() as u32).to_be_bytes());
+            body.extend(data);
+        }
+        let mut file = b"FORM".to_vec();
+        file.extend((body.len() as u32).to_be_bytes());
+        file.extend(body);
+
+        let mut reader = PcmReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.format.sample_rate == 44100 && reader.format.total_samples == 3);
+        assert!(read_all(&mut reader) == [1, -1, 32767]);
+        assert!(reader.tags == [("TITLE".to_string(), "Song".to_string())]);
+    }
+
+    #[test]
+    fn w64_8bit() {
+        let mut fmt = 1u16.to_le_bytes().to_vec();
+        fmt.extend(1u16.to_le_bytes());
+        fmt.extend(8000u32.to_le_bytes());
+        fmt.extend(8000u32.to_le_bytes());
+        fmt.extend(1u16.to_le_bytes());
+        fmt.extend(8u16.to_le_bytes());
+        let data = [0x80, 0x81, 0x00];
+
+        let mut file = W64_RIFF.to_vec();
+        file.extend(0u64.to_le_bytes());
+        file.extend(W64_WAVE);
+        for (guid, body) in [(W64_FMT, fmt.as_slice()), (W64_DATA, data.as_slice())] {
+            file.extend(guid);
+            file.extend((body.len() as u64 + 24).to_le_bytes());
+            file.extend(body);
+            file.resize(file.len().next_multiple_of(8), 0);
+        }
+
+        let mut reader = PcmReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.format.total_samples == 3);
+        assert!(read_all(&mut reader) == [0, 1, -128]);
+    }
+}
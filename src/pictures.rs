@@ -0,0 +1,175 @@
+use anyhow::Result;
+use flac_codec::metadata::{Picture, PictureType};
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+/// Rules applied to the embedded pictures of every file that gets rewritten
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PicturePolicy {
+    /// Remove all pictures
+    pub(crate) strip: bool,
+    /// Remove everything but the front cover
+    pub(crate) front_only: bool,
+    /// Write the front cover next to the file, unless one is already there
+    pub(crate) extract: bool,
+    /// Remove pictures identical to an earlier one
+    pub(crate) dedupe: bool,
+    /// Pictures larger than this are reported
+    pub(crate) max_size: Option<u64>,
+    /// Pictures larger than `max_size` are also removed
+    pub(crate) drop_oversized: bool,
+}
+
+fn extension(picture: &Picture) -> &'static str {
+    match picture.media_type.as_str() {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        _ => "jpg",
+    }
+}
+
+impl PicturePolicy {
+    /// Returns `true` if the policy leaves pictures untouched
+    pub(crate) fn is_noop(&self) -> bool {
+        !self.strip && !self.front_only && !self.extract && !self.dedupe && self.max_size.is_none()
+    }
+
+    /// Applies the policy to the pictures of the file at `filename`.
+    ///
+    /// Returns the pictures to keep along with notes worth reporting.
+    pub(crate) fn apply(
+        &self,
+        filename: &Path,
+        pictures: Vec<Picture>,
+    ) -> Result<(Vec<Picture>, Vec<String>)> {
+        let mut notes = Vec::new();
+
+        if self.extract
+            && let Some(cover) = pictures
+                .iter()
+                .find(|picture| picture.picture_type == PictureType::FrontCover)
+            && let Some(dir) = filename.parent()
+        {
+            let target = dir.join(format!("cover.{}", extension(cover)));
+            // files of the same album are processed in parallel, only one may write the cover
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&target)
+            {
+                Ok(mut file) => {
+                    if let Err(error) = file.write_all(&cover.data) {
+                        // a partial cover would block every later attempt to write it
+                        let _ = std::fs::remove_file(&target);
+                        return Err(error.into());
+                    }
+                    notes.push(format!("extracted cover to {}", target.to_string_lossy()));
+                }
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        if self.strip {
+            return Ok((Vec::new(), notes));
+        }
+
+        let mut kept: Vec<Picture> = Vec::with_capacity(pictures.len());
+        for picture in pictures {
+            if self.front_only && picture.picture_type != PictureType::FrontCover {
+                continue;
+            }
+            if self.dedupe && kept.iter().any(|other| other.data == picture.data) {
+                continue;
+            }
+            if let Some(max_size) = self.max_size
+                && picture.data.len() as u64 > max_size
+            {
+                notes.push(format!(
+                    "{} picture of {} bytes{}",
+                    picture.picture_type,
+                    picture.data.len(),
+                    if self.drop_oversized { " dropped" } else { "" }
+                ));
+                if self.drop_oversized {
+                    continue;
+                }
+            }
+            kept.push(picture);
+        }
+
+        Ok((kept, notes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(picture_type: PictureType, data: &[u8]) -> Picture {
+        Picture {
+            picture_type,
+            media_type: "image/png".to_string(),
+            description: String::new(),
+            width: 1,
+            height: 1,
+            color_depth: 24,
+            colors_used: None,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn dedupe_and_size() {
+        let policy = PicturePolicy {
+            dedupe: true,
+            max_size: Some(4),
+            drop_oversized: true,
+            ..PicturePolicy::default()
+        };
+        let pictures = vec![
+            picture(PictureType::FrontCover, &[1, 2, 3]),
+            picture(PictureType::FrontCover, &[1, 2, 3]),
+            picture(PictureType::BackCover, &[1, 2, 3, 4, 5]),
+        ];
+        let (kept, notes) = policy.apply(Path::new("a.flac"), pictures).unwrap();
+        assert!(kept.len() == 1 && notes.len() == 1);
+    }
+
+    #[test]
+    fn front_only() {
+        let policy = PicturePolicy {
+            front_only: true,
+            ..PicturePolicy::default()
+        };
+        let pictures = vec![
+            picture(PictureType::BackCover, &[1]),
+            picture(PictureType::FrontCover, &[2]),
+        ];
+        let (kept, _) = policy.apply(Path::new("a.flac"), pictures).unwrap();
+        assert!(kept.len() == 1 && kept[0].data == [2]);
+    }
+
+    #[test]
+    fn extract_cover() {
+        let dir = Path::new("./samples/extract");
+        std::fs::create_dir_all(dir).unwrap();
+        let policy = PicturePolicy {
+            extract: true,
+            ..PicturePolicy::default()
+        };
+        let back = vec![picture(PictureType::BackCover, &[1])];
+        let (_, skipped) = policy.apply(&dir.join("a.flac"), back).unwrap();
+        let front = vec![picture(PictureType::FrontCover, &[2])];
+        let (_, first) = policy.apply(&dir.join("a.flac"), front.clone()).unwrap();
+        let (_, second) = policy.apply(&dir.join("b.flac"), front).unwrap();
+        let cover = std::fs::read(dir.join("cover.png")).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(skipped.is_empty() && first.len() == 1 && second.is_empty());
+        assert!(cover == [2]);
+    }
+}
@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+
+/// Samples per analysed frame, a power of two for the FFT
+const FRAME: usize = 4096;
+/// Upper bound on analysed frames, long files are sampled at a wider stride
+const MAX_FRAMES: u64 = 2000;
+/// Highest frequency lossless CD audio is expected to reach
+const FULL_BAND: f64 = 22050.0;
+/// Power added before taking logarithms so digital silence stays finite
+const EPSILON: f64 = 1e-20;
+
+/// In-place iterative radix-2 FFT, `re` and `im` must have the same power of two length
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f64;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let odd_re = re[b] * cos - im[b] * sin;
+                let odd_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - odd_re;
+                im[b] = im[a] - odd_im;
+                re[a] += odd_re;
+                im[a] += odd_im;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+fn decibels(power: f64) -> f64 {
+    10.0 * (power + EPSILON).log10()
+}
+
+/// Signs of a lossy codec found in the spectrum of a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LossyReport {
+    /// Frequency in Hz above which the spectrum is empty
+    pub(crate) cutoff: u32,
+    /// Fraction of frames where the top band drops out while the rest plays on
+    pub(crate) holes: f64,
+    /// Heuristic likelihood of lossy origin, from 0 to 1
+    pub(crate) confidence: f64,
+}
+
+/// Accumulates the spectrum of interleaved samples fed to it frame by frame
+pub(crate) struct SpectrumAnalyzer {
+    sample_rate: u32,
+    channels: usize,
+    scale: f64,
+    /// Samples per channel skipped between analysed frames
+    skip: u64,
+    skipped: u64,
+    window: Vec<f64>,
+    pending: Vec<f64>,
+    /// Sum of the power spectra of all frames
+    power: Vec<f64>,
+    /// Level of the top band relative to the mid band, per frame with audible mids
+    top_levels: Vec<f64>,
+}
+
+impl SpectrumAnalyzer {
+    pub(crate) fn new(
+        sample_rate: u32,
+        channels: u32,
+        bits_per_sample: u32,
+        total_samples: Option<u64>,
+    ) -> Self {
+        let frames = total_samples.unwrap_or(0) / FRAME as u64;
+        SpectrumAnalyzer {
+            sample_rate,
+            channels: channels.max(1) as usize,
+            scale: 1.0 / (1u64 << bits_per_sample.saturating_sub(1)) as f64,
+            skip: frames.saturating_sub(1) / MAX_FRAMES * FRAME as u64,
+            skipped: 0,
+            window: (0..FRAME)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FRAME as f64).cos())
+                .collect(),
+            pending: Vec::with_capacity(FRAME),
+            power: vec![0.0; FRAME / 2],
+            top_levels: Vec::new(),
+        }
+    }
+
+    fn bin(&self, frequency: f64) -> usize {
+        ((frequency * FRAME as f64 / self.sample_rate as f64) as usize).min(FRAME / 2 - 1)
+    }
+
+    fn frequency(&self, bin: usize) -> f64 {
+        bin as f64 * self.sample_rate as f64 / FRAME as f64
+    }
+
+    /// Feeds interleaved samples, downmixed to mono before analysis.
+    pub(crate) fn feed(&mut self, samples: &[i32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            if self.skipped < self.skip {
+                self.skipped += 1;
+                continue;
+            }
+            let sum = frame.iter().map(|sample| f64::from(*sample)).sum::<f64>();
+            self.pending.push(sum * self.scale / self.channels as f64);
+            if self.pending.len() == FRAME {
+                self.analyse_frame();
+                self.pending.clear();
+                self.skipped = 0;
+            }
+        }
+    }
+
+    fn analyse_frame(&mut self) {
+        let mut re = self
+            .pending
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, weight)| sample * weight)
+            .collect::<Vec<_>>();
+        let mut im = vec![0.0; FRAME];
+        fft(&mut re, &mut im);
+
+        let power = re
+            .iter()
+            .zip(&im)
+            .take(FRAME / 2)
+            .map(|(re, im)| re * re + im * im)
+            .collect::<Vec<_>>();
+        for (total, bin) in self.power.iter_mut().zip(&power) {
+            *total += bin;
+        }
+
+        // lossy encoders drop the top band in frames where bits run short
+        let mids = power[self.bin(2000.0)..self.bin(8000.0)]
+            .iter()
+            .sum::<f64>();
+        let nyquist = self.sample_rate as f64 / 2.0;
+        let top_start = self.bin(16000.0_f64.min(nyquist * 0.7));
+        let top_end = self.bin(FULL_BAND.min(nyquist) * 0.95);
+        if decibels(mids) > -20.0 && top_end > top_start {
+            let top = power[top_start..top_end].iter().sum::<f64>();
+            self.top_levels.push(decibels(top) - decibels(mids));
+        }
+    }
+
+    /// Summarises everything fed so far, `None` if the audio was too short to analyse.
+    pub(crate) fn finish(&self) -> Option<LossyReport> {
+        let frames = self.power.iter().any(|bin| *bin > 0.0);
+        if !frames {
+            return None;
+        }
+
+        // smooth over roughly 50 Hz so single quiet bins don't look like a cutoff
+        let width = (self.bin(50.0) / 2).max(1);
+        let levels = (0..self.power.len())
+            .map(|bin| {
+                let range = bin.saturating_sub(width)..(bin + width + 1).min(self.power.len());
+                let count = range.len() as f64;
+                decibels(self.power[range].iter().sum::<f64>() / count)
+            })
+            .collect::<Vec<_>>();
+
+        let nyquist = self.sample_rate as f64 / 2.0;
+        let band_end = self.bin(FULL_BAND.min(nyquist));
+        let mut sorted = levels[..=band_end].to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let floor = sorted[sorted.len() / 20];
+
+        let cutoff_bin = (0..=band_end)
+            .rev()
+            .find(|bin| levels[*bin] > floor + 20.0)
+            .unwrap_or(0);
+        let cutoff = self.frequency(cutoff_bin);
+
+        // codecs cut with a brickwall filter, natural rolloff is far gentler
+        let below = levels[self.bin((cutoff - 500.0).max(0.0))];
+        let above = levels[self.bin((cutoff + 500.0).min(nyquist)).min(band_end)];
+        let steepness = ((below - above - 15.0) / 30.0).clamp(0.0, 1.0);
+
+        let holes = if self.top_levels.is_empty() {
+            0.0
+        } else {
+            let mut sorted = self.top_levels.clone();
+            sorted.sort_by(f64::total_cmp);
+            let median = sorted[sorted.len() / 2];
+            sorted
+                .iter()
+                .filter(|level| **level < median - 30.0)
+                .count() as f64
+                / sorted.len() as f64
+        };
+        let holes_score = (holes * 4.0).min(1.0);
+
+        let band_limited = cutoff < FULL_BAND.min(nyquist) * 0.95;
+        let confidence = if band_limited {
+            0.75 * steepness + 0.25 * holes_score
+        } else {
+            0.25 * holes_score
+        };
+
+        Some(LossyReport {
+            cutoff: cutoff as u32,
+            holes,
+            confidence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic noise made of sines up to `top` Hz
+    fn noise(top: f64, seconds: usize) -> Vec<i32> {
+        let mut seed = 12345u64;
+        let tones = (0..400)
+            .map(|i| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (
+                    top * (i as f64 + 0.5) / 400.0,
+                    (seed >> 11) as f64 / (1u64 << 53) as f64,
+                )
+            })
+            .collect::<Vec<_>>();
+        (0..44100 * seconds)
+            .map(|n| {
+                let t = n as f64 / 44100.0;
+                let value = tones
+                    .iter()
+                    .map(|(frequency, phase)| (2.0 * PI * (frequency * t + phase)).sin())
+                    .sum::<f64>();
+                (value * 300.0) as i32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fft_peak() {
+        let mut re = (0..64)
+            .map(|i| (2.0 * PI * 5.0 * i as f64 / 64.0).cos())
+            .collect::<Vec<_>>();
+        let mut im = vec![0.0; 64];
+        fft(&mut re, &mut im);
+        let peak = (0..32)
+            .max_by(|a, b| re[*a].hypot(im[*a]).total_cmp(&re[*b].hypot(im[*b])))
+            .unwrap();
+        assert!(peak == 5);
+    }
+
+    #[test]
+    fn brickwall() {
+        let mut full = SpectrumAnalyzer::new(44100, 1, 16, None);
+        full.feed(&noise(22000.0, 2));
+        let full = full.finish().unwrap();
+
+        let mut cut = SpectrumAnalyzer::new(44100, 1, 16, None);
+        cut.feed(&noise(16000.0, 2));
+        let cut = cut.finish().unwrap();
+
+        assert!(full.confidence < 0.5 && cut.confidence > 0.5);
+        assert!(cut.cutoff.abs_diff(16000) < 300);
+    }
+}
@@ -0,0 +1,167 @@
+use flac_codec::metadata::VorbisComment;
+
+/// Tags that are stripped unless configured otherwise
+const DEFAULT_STRIP: [&str; 3] = ["encoded_by", "encodedby", "encoder"];
+
+/// Rules applied to the Vorbis comment of every file that gets rewritten
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TagPolicy {
+    /// Fields that are removed
+    pub(crate) strip: Vec<String>,
+    /// Fields that are never removed, even if listed in `strip`
+    pub(crate) keep: Vec<String>,
+    /// Field renames, applied before anything else
+    pub(crate) renames: Vec<(String, String)>,
+    /// Uppercase all field names
+    pub(crate) uppercase: bool,
+    /// Trim whitespace around values
+    pub(crate) trim: bool,
+    /// Drop repeated identical values of a field
+    pub(crate) dedupe: bool,
+    /// Drop fields with an empty value
+    pub(crate) drop_empty: bool,
+}
+
+impl Default for TagPolicy {
+    fn default() -> Self {
+        TagPolicy {
+            strip: DEFAULT_STRIP.iter().map(|tag| tag.to_string()).collect(),
+            keep: Vec::new(),
+            renames: Vec::new(),
+            uppercase: false,
+            trim: false,
+            dedupe: false,
+            drop_empty: false,
+        }
+    }
+}
+
+/// Whether `name` can be a Vorbis comment field name, which is printable ASCII without '='
+pub(crate) fn is_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| (0x20..0x7E).contains(&byte) && byte != b'=')
+}
+
+fn contains(list: &[String], field: &str) -> bool {
+    list.iter().any(|item| item.eq_ignore_ascii_case(field))
+}
+
+impl TagPolicy {
+    /// Stable description of the policy, stored with every file it was applied to
+    pub(crate) fn fingerprint(&self) -> String {
+        let renames = self
+            .renames
+            .iter()
+            .map(|(from, to)| format!("{from}>{to}"))
+            .collect::<Vec<_>>();
+        format!(
+            "strip={};keep={};rename={};upper={};trim={};dedupe={};empty={}",
+            self.strip.join(","),
+            self.keep.join(","),
+            renames.join(","),
+            self.uppercase,
+            self.trim,
+            self.dedupe,
+            self.drop_empty
+        )
+    }
+
+    /// Applies the policy to `comment`, returning `true` if anything changed.
+    ///
+    /// The vendor string is left alone.
+    pub(crate) fn apply(&self, comment: &mut VorbisComment) -> bool {
+        let mut fields: Vec<String> = Vec::with_capacity(comment.fields.len());
+
+        for entry in &comment.fields {
+            // entries without a separator are broken, but not ours to judge
+            let Some((name, value)) = entry.split_once('=') else {
+                fields.push(entry.clone());
+                continue;
+            };
+
+            let name = self
+                .renames
+                .iter()
+                .find(|(from, _)| from.eq_ignore_ascii_case(name))
+                .map_or(name, |(_, to)| to.as_str());
+
+            if contains(&self.strip, name) && !contains(&self.keep, name) {
+                continue;
+            }
+
+            let name = if self.uppercase {
+                name.to_ascii_uppercase()
+            } else {
+                name.to_string()
+            };
+            let value = if self.trim { value.trim() } else { value };
+            if self.drop_empty && value.is_empty() {
+                continue;
+            }
+
+            let entry = format!("{name}={value}");
+            if self.dedupe
+                && fields.iter().any(|existing| {
+                    existing
+                        .split_once('=')
+                        .is_some_and(|(other, other_value)| {
+                            other.eq_ignore_ascii_case(&name) && other_value == value
+                        })
+                })
+            {
+                continue;
+            }
+            fields.push(entry);
+        }
+
+        let changed = fields != comment.fields;
+        comment.fields = fields;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(fields: &[&str]) -> VorbisComment {
+        VorbisComment {
+            vendor_string: "test".to_string(),
+            fields: fields.iter().map(|field| field.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let mut tags = comment(&["TITLE=a", "ENCODER=x", "Encoded_By=y"]);
+        assert!(TagPolicy::default().apply(&mut tags));
+        assert!(tags.fields == ["TITLE=a"]);
+        assert!(!TagPolicy::default().apply(&mut tags));
+    }
+
+    #[test]
+    fn full_policy() {
+        let policy = TagPolicy {
+            keep: vec!["encoder".to_string()],
+            renames: vec![("album artist".to_string(), "albumartist".to_string())],
+            uppercase: true,
+            trim: true,
+            dedupe: true,
+            drop_empty: true,
+            ..TagPolicy::default()
+        };
+        let mut tags = comment(&[
+            "album artist= b ",
+            "artist=c",
+            "ARTIST=c ",
+            "encoder=x",
+            "comment= ",
+            "broken",
+        ]);
+        policy.apply(&mut tags);
+        assert!(tags.fields == ["ALBUMARTIST=b", "ARTIST=c", "ENCODER=x", "broken"]);
+        assert!(policy.fingerprint() != TagPolicy::default().fingerprint());
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use std::{
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Shared bandwidth cap, every thread drawing from the same budget
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `bytes` of I/O, sleeping if the cap has been exceeded.
+    pub(crate) fn consume(&self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let wait = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            // idle time doesn't pile up into a burst
+            *next_free = (*next_free).max(now) + cost;
+            next_free.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            sleep(wait);
+        }
+    }
+}
+
+/// Reader that draws every read from an optional [`Throttle`]
+pub(crate) struct Throttled<R> {
+    inner: R,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl<R> Throttled<R> {
+    pub(crate) fn new(inner: R, throttle: Option<Arc<Throttle>>) -> Self {
+        Throttled { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(read as u64);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Throttled<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Lowers CPU priority to `nice` and optionally moves I/O to the idle class.
+///
+/// Must run before any worker threads are spawned, since they inherit it.
+#[cfg(unix)]
+pub(crate) fn lower_priority(nice: Option<i32>, idle_io: bool) -> Result<()> {
+    if let Some(nice) = nice {
+        // the `which` argument type differs between libc targets
+        #[allow(clippy::useless_conversion)]
+        let result = unsafe { libc::setpriority(libc::PRIO_PROCESS.try_into()?, 0, nice) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if idle_io {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = idle_io;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lower_priority(_nice: Option<i32>, _idle_io: bool) -> Result<()> {
+    Ok(())
+}
+
+/// One minute system load average, if the platform reports one
+#[cfg(unix)]
+pub(crate) fn load_average() -> Option<f64> {
+    let mut load = [0f64; 1];
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } == 1 {
+        Some(load[0])
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn load_average() -> Option<f64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_rate() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now();
+        for _ in 0..5 {
+            throttle.consume(100);
+        }
+        throttle.consume(1000);
+        assert!(start.elapsed() >= Duration::from_millis(1000))
+    }
+
+    #[test]
+    fn throttled_read() {
+        let data = vec![7u8; 64];
+        let throttle = Arc::new(Throttle::new(1 << 20));
+        let mut reader = Throttled::new(data.as_slice(), Some(throttle));
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert!(output == data)
+    }
+}